@@ -1,409 +1,1835 @@
-use crate::types::{LangVal, Result, Env, env_push, env_set};
-use crate::eval::{eval, eval_ast};
+use crate::types::{LangVal, LangError, ErrorKind, Result, Env, Hashmap, MapKey, Meta, Clause, Atom, env_push, env_set, env_root, check_arity, seq, seq_len, seq_nth};
+use crate::eval::{eval, eval_ast, apply_fn, destructure, macroexpand, macroexpand_1};
 use crate::reader;
 use itertools::{Itertools, zip};
-use crate::printer::pr_str;
+use crate::printer::{pr_str, pr_frame};
+use crate::numeric::{self, Num};
+use std::cmp::Ordering;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::{BufRead, Read};
+
+// takes the numbers out of the arguments of the arithmetic function name
+fn numbers(name: &str, args: &[LangVal]) -> Result<Vec<Num>> {
+    args.iter()
+        .map(|arg| Num::from_val(arg).ok_or_else(|| LangError::type_error(name, "a number", arg)))
+        .collect()
+}
 
 fn add(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    let mut res: f64 = 0.0;
+    let mut res = Num::Int(0);
 
-    for arg in args {
-        if let LangVal::Number(n) = arg {
-            res += n;
-        } else {
-            Err("+ expected a number")?;
-        }
+    for n in numbers("+", &args)? {
+        res = numeric::add(res, n);
     }
 
-    Ok(LangVal::Number(res))
+    Ok(res.into_val())
 }
 
 fn multiply(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    let mut res: f64 = 1.0;
+    let mut res = Num::Int(1);
 
-    for arg in args {
-        if let LangVal::Number(n) = arg {
-            res *= n;
-        } else {
-            Err("* expected a number")?;
-        }
+    for n in numbers("*", &args)? {
+        res = numeric::mul(res, n);
     }
 
-    Ok(LangVal::Number(res))
+    Ok(res.into_val())
 }
 
 fn subtract(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
 
-    if args.len() == 0 {
-        Err("- got too few arguments")?;
-    }
+    check_arity("-", &args, 1, None)?;
+
+    let mut nums = numbers("-", &args)?.into_iter();
+    let first = nums.next().unwrap();
+
     if args.len() == 1 {
-        if let LangVal::Number(n) = args[0] {
-            return Ok(LangVal::Number(-1.0 * n));
-        } else {
-            Err("- expected a number")?;
-        }
+        return Ok(numeric::neg(first).into_val());
     }
 
-    let mut set: bool = false;
-    let mut res: f64 = 1.0;
+    let mut res = first;
 
-    for arg in args {
-        if let LangVal::Number(n) = arg {
-            if set {
-                res -= n;
-            } else {
-                set = true;
-                res = n;
-            }
-        } else {
-            Err("+ expected a number")?;
-        }
+    for n in nums {
+        res = numeric::sub(res, n);
     }
 
-    Ok(LangVal::Number(res))
+    Ok(res.into_val())
 }
 
 fn divide(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
 
-    if args.len() == 0 {
-        Err("/ got too few arguments")?;
-    }
+    check_arity("/", &args, 1, None)?;
+
+    let mut nums = numbers("/", &args)?.into_iter();
+    let first = nums.next().unwrap();
+
     if args.len() == 1 {
-        if let LangVal::Number(n) = args[0] {
-            if n == 0.0 {
-                Err("Division by 0")?;
-            } else {
-                return Ok(LangVal::Number(1.0 / n));
-            }
-        } else {
-            Err("/ expected a number")?;
-        }
+        return Ok(numeric::div(Num::Int(1), first)?.into_val());
     }
 
-    let mut set: bool = false;
-    let mut res: f64 = 1.0;
+    let mut res = first;
 
-    for arg in args {
-        if let LangVal::Number(n) = arg {
-            if set {
-                if n == 0.0 {
-                    Err("Division by 0")?;
-                } else {
-                    res /= n;
-                }
-            } else {
-                set = true;
-                res = n;
-            }
-        } else {
-            Err("+ expected a number")?;
-        }
+    for n in nums {
+        res = numeric::div(res, n)?;
     }
 
-    Ok(LangVal::Number(res))
+    Ok(res.into_val())
 }
 
-fn fn_def(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
-    if args.len() != 2 {
-        Err(format!("def! expected 2 arguments, got {}", args.len()))?
+fn fn_numerator(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("numerator", &args, 1, Some(1))?;
+
+    match Num::from_val(&args[0]).as_ref().and_then(numeric::numerator_denominator) {
+        Some((numer, _)) => Ok(Num::Big(numer).into_val()),
+        None => Err(LangError::type_error("numerator", "an integer or ratio", &args[0]))?
+    }
+}
+
+fn fn_denominator(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("denominator", &args, 1, Some(1))?;
+
+    match Num::from_val(&args[0]).as_ref().and_then(numeric::numerator_denominator) {
+        Some((_, denom)) => Ok(Num::Big(denom).into_val()),
+        None => Err(LangError::type_error("denominator", "an integer or ratio", &args[0]))?
+    }
+}
+
+// evaluates form to bind it to the symbol s, functions remember the first name they were given for stack traces
+fn eval_named(s: &str, form: LangVal, env: &Env) -> Result<LangVal> {
+    let mut val = eval(form, env.clone())?;
+
+    if let LangVal::DefinedFunction {name, ..} = &mut val {
+        name.get_or_insert_with(|| s.to_string());
+    }
+
+    Ok(val)
+}
+
+fn keyword_key(name: &str) -> MapKey {
+    MapKey(LangVal::keyword(name))
+}
+
+// metadata def! gives the values it binds, on top of whatever metadata they already had
+fn def_meta(val: &LangVal, s: &str, doc: Option<String>, form: &LangVal) -> LangVal {
+    let mut mp = match val.meta().and_then(|meta| meta.value.as_deref()) {
+        Some(LangVal::Hashmap(mp, _)) => mp.clone(),
+        _ => Hashmap::default()
+    };
+
+    mp.insert(keyword_key("name"), LangVal::Symbol(s.to_string()));
+
+    if let Some(doc) = doc {
+        mp.insert(keyword_key("doc"), LangVal::String(doc));
     }
+    if let Some(span) = form.meta().and_then(|meta| meta.span.as_ref()) {
+        mp.insert(keyword_key("file"), LangVal::String(span.source.name.clone()));
+        mp.insert(keyword_key("line"), LangVal::Integer(span.line as i64));
+        mp.insert(keyword_key("column"), LangVal::Integer(span.col as i64));
+    }
+
+    LangVal::hashmap(mp)
+}
+
+fn fn_def(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("def!", &args, 2, Some(3))?;
+
+    // (def! name "docstring" value)
+    let (doc, form) = match &args[..] {
+        [_, LangVal::String(doc), form] => (Some(doc.clone()), form.clone()),
+        [_, doc, _] => Err(format!("def! expected a docstring before the value, got {}", pr_str(doc, true)))?,
+        _ => (None, args[1].clone())
+    };
 
     match &args[0] {
        LangVal::Symbol(s) => {
-           let val = eval(args[1].clone(), env.clone())?;
+           let mut val = eval_named(s, form.clone(), &env)?;
+
+           let meta = def_meta(&val, s, doc, &form);
+           if let Some(slot) = val.meta_mut() {
+               slot.value = Some(Rc::new(meta));
+           }
 
            env_set(&env, s, val.clone());
 
            Ok(val)
        }
-       _ => Err("Cannot use def! on a non-symbol")?
+       // (def! [a b] value) defines a and b
+       pattern => {
+           let val = eval(form, env.clone())?;
+           destructure("def!", pattern, val.clone(), &env)?;
+
+           Ok(val)
+       }
     }
 }
 
-fn fn_let(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
-    if args.len() != 2 {
-        Err(format!("let* expected 2 arguments, got {}", args.len()))?
+fn fn_defmacro(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("defmacro!", &args, 2, Some(2))?;
+
+    match &args[0] {
+        LangVal::Symbol(s) => {
+            let mut val = eval(args[1].clone(), env.clone())?;
+
+            match &mut val {
+                LangVal::DefinedFunction {is_macro, name, ..} => {
+                    *is_macro = true;
+                    name.get_or_insert_with(|| s.clone());
+                }
+                _ => Err(LangError::type_error("defmacro!", "a function", &val))?
+            }
+
+            env_set(&env, s, val.clone());
+
+            Ok(val)
+        }
+        _ => Err(format!("defmacro! expected a symbol to define, got {}", pr_str(&args[0], true)))?
     }
+}
+
+fn fn_macroexpand_1(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("macroexpand-1", &args, 1, Some(1))?;
+
+    Ok(macroexpand_1(&args[0], &env)?.unwrap_or_else(|| args[0].clone()))
+}
+
+fn fn_macroexpand(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("macroexpand", &args, 1, Some(1))?;
+
+    macroexpand(args[0].clone(), &env)
+}
+
+fn fn_let(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    check_arity("let*", &args, 2, Some(2))?;
 
     let mut binds: Vec<LangVal> = Default::default();
 
     match &args[0] {
-        LangVal::List(v)|LangVal::Vector(v) => {
+        LangVal::List(v, _)|LangVal::Vector(v, _) => {
             binds = v.clone();
         }
         _ => {
-            Err("First argument of let* must be list or vector")?;
+            Err(format!("let* expected a list or vector of bindings, got {}", pr_str(&args[0], true)))?;
         }
     };
 
-    if binds.len() % 2 != 0 {
+    if !binds.len().is_multiple_of(2) {
         Err("Second argument of let must have even parity")?;
     }
 
     let env = env_push(Some(env));
 
     for (k, v) in binds.into_iter().tuples() {
-        fn_def(vec![k, v], env.clone())?;
+        let val = match &k {
+            LangVal::Symbol(s) => eval_named(s, v, &env)?,
+            _ => eval(v, env.clone())?
+        };
+
+        destructure("let*", &k, val, &env)?;
     }
 
     Ok((args[1].clone(), env))
 }
 
 fn fn_do(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
-    let n = args.len();
-
-    if n == 0 {
-        Err("do expected at least 1 argument, got 0")?;
-    }
+    check_arity("do", &args, 1, None)?;
 
     // evaluate the first n-1 arguments
-    eval_ast(LangVal::List(args[..(args.len()-1)].to_vec()), env.clone())?;
+    eval_ast(LangVal::list(args[..(args.len()-1)].to_vec()), env.clone())?;
 
 
     Ok((args[args.len()-1].clone(), env))
 }
 
-fn fn_list(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    Ok(LangVal::List(args))
+fn fn_quote(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("quote", &args, 1, Some(1))?;
+
+    Ok(args[0].clone())
 }
 
-fn fn_list_q(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    if args.len() != 1 {
-        Err(format!("list? expected 1 argument, got {}", args.len()))?;
-    }
-    match &args[0] {
-        LangVal::List(_) => Ok(LangVal::Boolean(true)),
-        _ => Ok(LangVal::Boolean(false))
-    }
+// wraps an already evaluated value so handing it back to the TCO loop doesn't evaluate it again
+fn quoted(val: LangVal) -> LangVal {
+    LangVal::list(vec![LangVal::SpecialFunction(fn_quote), val])
 }
 
-fn fn_empty_q(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    if args.len() != 1 {
-        Err(format!("empty? expected 1 argument, got {}", args.len()))?;
+// returns the argument of an (unquote x) or (splice-unquote x) form, in either reader or list shape
+fn special_arg(ast: &LangVal, name: &str) -> Option<LangVal> {
+    match ast {
+        LangVal::WithSpecial((s, inner)) if s == name => Some((**inner).clone()),
+        LangVal::List(v, _) if v.len() == 2 => match &v[0] {
+            LangVal::Symbol(s) if s == name => Some(v[1].clone()),
+            _ => None
+        },
+        _ => None
     }
-    match &args[0] {
-        LangVal::List(v)|
-        LangVal::Vector(v) => Ok(LangVal::Boolean(v.len() == 0)),
-        _ => Err("empty? expected a list")?
+}
+
+// builds the (cons ...)/(concat ...) chain that reconstructs a quasiquoted sequence
+fn quasiquote_seq(elems: Vec<LangVal>) -> Result<LangVal> {
+    let mut ret = LangVal::list(vec![]);
+
+    for elem in elems.into_iter().rev() {
+        ret = match special_arg(&elem, "splice-unquote") {
+            Some(inner) => LangVal::list(vec![LangVal::Symbol("concat".to_string()), inner, ret]),
+            None => LangVal::list(vec![LangVal::Symbol("cons".to_string()), quasiquote_expand(elem)?, ret])
+        };
     }
+
+    Ok(ret)
 }
 
-fn fn_count(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    if args.len() != 1 {
-        Err(format!("count expected 1 argument, got {}", args.len()))?;
+fn quasiquote_expand(ast: LangVal) -> Result<LangVal> {
+    if let Some(inner) = special_arg(&ast, "unquote") {
+        return Ok(inner);
     }
-    match &args[0] {
-        LangVal::List(v)|
-        LangVal::Vector(v) => Ok(LangVal::Number(v.len() as f64)),
-        LangVal::Nil => Ok(LangVal::Number(0.0)),
-        _ => Err("count expected a list")?
+
+    match ast {
+        LangVal::WithSpecial((name, inner)) => {
+            quasiquote_expand(LangVal::list(vec![LangVal::Symbol(name), (*inner).clone()]))
+        }
+        LangVal::List(elems, _) => quasiquote_seq(elems),
+        LangVal::Vector(elems, _) => {
+            Ok(LangVal::list(vec![LangVal::Symbol("vec".to_string()), quasiquote_seq(elems)?]))
+        }
+        // keys and values are walked like a sequence of k1 v1 k2 v2 and turned back into a map
+        LangVal::Hashmap(mp, _) => {
+            let entries = mp.into_iter().flat_map(|(k, v)| vec![k.to_val(), v]).collect();
+
+            Ok(LangVal::list(vec![LangVal::Symbol("apply".to_string()),
+                                  LangVal::Symbol("hash-map".to_string()),
+                                  quasiquote_seq(entries)?]))
+        }
+        LangVal::Symbol(_) => {
+            Ok(LangVal::list(vec![LangVal::Symbol("quote".to_string()), ast]))
+        }
+        _ => Ok(ast)
     }
 }
 
-fn fn_if(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
-    if args.len() < 2 {
-        Err(format!("if expected at least 2 arguments, got {}", args.len()))?;
+fn fn_quasiquote(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    check_arity("quasiquote", &args, 1, Some(1))?;
+
+    Ok((quasiquote_expand(args[0].clone())?, env))
+}
+
+fn fn_quasiquoteexpand(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("quasiquoteexpand", &args, 1, Some(1))?;
+
+    quasiquote_expand(args[0].clone())
+}
+
+fn fn_unquote(_: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    Err("unquote used outside of quasiquote")?
+}
+
+fn fn_splice_unquote(_: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    Err("splice-unquote used outside of quasiquote")?
+}
+
+fn integer(name: &str, val: &LangVal) -> Result<i64> {
+    match val {
+        LangVal::Integer(n) => Ok(*n),
+        _ => Err(LangError::type_error(name, "an integer", val))
     }
-    if args.len() > 3 {
-        Err(format!("if expected at most 3 arguments, got {}", args.len()))?;
+}
+
+fn fn_cons(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("cons", &args, 2, Some(2))?;
+
+    let mut ret = vec![args[0].clone()];
+    ret.extend(seq("cons", &args[1])?);
+
+    Ok(LangVal::list(ret))
+}
+
+fn fn_concat(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    let mut ret = vec![];
+
+    for arg in &args {
+        ret.extend(seq("concat", arg)?);
     }
 
-    let true_case = args[1].clone();
-    let false_case = if args.len() == 3 {args[2].clone()} else {LangVal::Nil};
+    Ok(LangVal::list(ret))
+}
 
-    match eval(args[0].clone(), env.clone())? {
-        LangVal::Boolean(b) => {
-            if b {
-                Ok((true_case, env))
-            } else {
-                Ok((false_case, env))
-            }
-        },
-        LangVal::Number(_) => {
-            Ok((true_case, env))
+fn fn_vec(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("vec", &args, 1, Some(1))?;
+
+    Ok(LangVal::vector(seq("vec", &args[0])?))
+}
+
+// adds to the front of lists and the end of vectors, wherever it's cheapest
+fn fn_conj(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("conj", &args, 1, None)?;
+
+    match &args[0] {
+        LangVal::List(v, _) => {
+            let mut ret: Vec<LangVal> = args[1..].iter().rev().cloned().collect();
+            ret.extend(v.iter().cloned());
+            Ok(LangVal::list(ret))
         }
-        LangVal::List(_)|LangVal::Vector(_) => {
-            Ok((true_case, env))
+        LangVal::Nil => Ok(LangVal::list(args[1..].iter().rev().cloned().collect())),
+        LangVal::Vector(v, _) => {
+            let mut ret = v.clone();
+            ret.extend(args[1..].iter().cloned());
+            Ok(LangVal::vector(ret))
         }
-        LangVal::String(_) => {
-            Ok((true_case, env))
+        // entries are [key value] vectors or whole maps to merge in
+        LangVal::Hashmap(mp, _) => {
+            let mut ret = LangVal::hashmap(mp.clone());
+
+            for entry in &args[1..] {
+                ret = match entry {
+                    LangVal::Vector(kv, _) if kv.len() == 2 => fn_assoc(vec![ret, kv[0].clone(), kv[1].clone()], env.clone())?,
+                    LangVal::Hashmap(_, _) => fn_merge(vec![ret, entry.clone()], env.clone())?,
+                    _ => Err(LangError::type_error("conj", "a [key value] vector or hashmap", entry))?
+                };
+            }
+
+            Ok(ret)
         }
-        LangVal::Nil => Ok((false_case, env)),
-        _ => Err("if expected a boolean as first argument")?
+        _ => Err(LangError::type_error("conj", "a list, vector, hashmap or nil", &args[0]))
     }
 }
 
-fn fn_eq(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
-    if args.len() != 2 {
-        Err(format!("= expected exactly 2 arguments, got {}", args.len()))?;
+fn fn_first(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("first", &args, 1, Some(1))?;
+
+    Ok(seq_nth("first", &args[0], 0)?.unwrap_or(LangVal::Nil))
+}
+
+fn fn_rest(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("rest", &args, 1, Some(1))?;
+
+    Ok(LangVal::list(seq("rest", &args[0])?.into_iter().skip(1).collect()))
+}
+
+// (nth coll index default), errors out of range unless a default is given
+fn fn_nth(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("nth", &args, 2, Some(3))?;
+
+    let len = seq_len("nth", &args[0])?;
+    let i = integer("nth", &args[1])?;
+
+    match (if i < 0 || i as usize >= len { None } else { seq_nth("nth", &args[0], i as usize)? }, args.get(2)) {
+        (Some(val), _) => Ok(val),
+        (None, Some(default)) => Ok(default.clone()),
+        (None, None) => Err(format!("nth index {} out of range for a collection of {}", i, len))?
     }
+}
 
-    match (&args[0], &args[1]) {
-        (LangVal::Number(a), LangVal::Number(b)) => Ok(LangVal::Boolean(a == b)),
-        (LangVal::Boolean(a), LangVal::Boolean(b)) => Ok(LangVal::Boolean(a == b)),
-        (LangVal ::String(a), LangVal::String(b))=> Ok(LangVal::Boolean(a == b)),
-        (LangVal::Nil, LangVal::Nil) => Ok(LangVal::Boolean(true)),
-        (LangVal::List(v1), LangVal::List(v2))|
-        (LangVal::Vector(v1), LangVal::Vector(v2))|
-        (LangVal::Vector(v1), LangVal::List(v2))|
-        (LangVal::List(v1), LangVal::Vector(v2)) => {
-            if v1.len() != v2.len() {
-                return Ok(LangVal::Boolean(false))
-            }
-            for (i, j) in zip(v1, v2) {
-                if !fn_eq(vec![i.clone(), j.clone()], env.clone())?.try_boolean().unwrap() {
-                    return Ok(LangVal::Boolean(false));
-                }
-            }
-            Ok(LangVal::Boolean(true))
-        }
-        (_, _) => Ok(LangVal::Boolean(false))
+fn fn_last(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("last", &args, 1, Some(1))?;
+
+    match seq_len("last", &args[0])? {
+        0 => Ok(LangVal::Nil),
+        len => Ok(seq_nth("last", &args[0], len - 1)?.unwrap_or(LangVal::Nil))
     }
 }
 
-fn fn_greater(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    if args.len() != 2 {
-        Err(format!("> expected exactly 2 arguments, got {}", args.len()))?;
+// negative counts are the same as 0
+fn fn_take(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("take", &args, 2, Some(2))?;
+
+    let n = integer("take", &args[0])?.max(0) as usize;
+
+    Ok(LangVal::list(seq("take", &args[1])?.into_iter().take(n).collect()))
+}
+
+fn fn_drop(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("drop", &args, 2, Some(2))?;
+
+    let n = integer("drop", &args[0])?.max(0) as usize;
+
+    Ok(LangVal::list(seq("drop", &args[1])?.into_iter().skip(n).collect()))
+}
+
+fn fn_reverse(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("reverse", &args, 1, Some(1))?;
+
+    Ok(LangVal::list(seq("reverse", &args[0])?.into_iter().rev().collect()))
+}
+
+// (range end), (range start end) or (range start end step), end not included
+fn fn_range(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("range", &args, 1, Some(3))?;
+
+    let nums = numbers("range", &args)?;
+    let (start, end, step) = match &nums[..] {
+        [end] => (Num::Int(0), end.clone(), Num::Int(1)),
+        [start, end] => (start.clone(), end.clone(), Num::Int(1)),
+        [start, end, step] => (start.clone(), end.clone(), step.clone()),
+        _ => unreachable!()
+    };
+
+    if step.is_zero() {
+        Err("range step can't be 0")?;
+    }
+
+    if nums.iter().any(|n| matches!(n, Num::Float(f) if f.is_infinite())) {
+        Err("range can't take an infinite bound or step")?;
     }
 
-    match (&args[0], &args[1]) {
-        (LangVal::Number(a), LangVal::Number(b)) => Ok(LangVal::Boolean(a > b)),
-        (_, _) => Err("Cannot compare given values")?
+    // counting up stops once we're past end, counting down once we're under it
+    let going = if numeric::cmp(&step, &Num::Int(0)) == Some(Ordering::Greater) { Ordering::Less } else { Ordering::Greater };
+    let mut ret = vec![];
+    let mut cur = start;
+
+    while numeric::cmp(&cur, &end) == Some(going) {
+        ret.push(cur.clone().into_val());
+
+        // a step too small to change a big float would repeat it forever
+        let next = numeric::add(cur.clone(), step.clone());
+        if numeric::cmp(&next, &cur) == Some(Ordering::Equal) {
+            break;
+        }
+        cur = next;
     }
+
+    Ok(LangVal::list(ret))
 }
 
-fn fn_fn(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
-    if args.len() != 2 {
-        Err(format!("fn* expected exactly 2 arguments, got {}", args.len()))?;
+// (apply f a b [c d]) is (f a b c d)
+fn fn_apply(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("apply", &args, 2, None)?;
+
+    let mut func_args = args[1..args.len()-1].to_vec();
+    func_args.extend(seq("apply", &args[args.len()-1])?);
+
+    apply_fn(args[0].clone(), func_args, env)
+}
+
+// with more than one collection f gets an element of each, stopping at the shortest
+fn fn_map(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("map", &args, 2, None)?;
+
+    let colls = args[1..].iter().map(|arg| seq("map", arg)).collect::<Result<Vec<_>>>()?;
+    let len = colls.iter().map(Vec::len).min().unwrap();
+    let mut ret = vec![];
+
+    for i in 0..len {
+        ret.push(apply_fn(args[0].clone(), colls.iter().map(|c| c[i].clone()).collect(), env.clone())?);
     }
 
-    let mut symbols = vec![];
+    Ok(LangVal::list(ret))
+}
 
-    match &args[0] {
-        LangVal::List(v)|LangVal::Vector(v) => {
-            for i in v {
-                match i {
-                    LangVal::Symbol(s) => {
-                        symbols.push(s.clone());
-                    }
-                    _ => Err("fn* expected list of symbols as first argument")?
-                }
-            }
+fn fn_filter(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("filter", &args, 2, Some(2))?;
+
+    let mut ret = vec![];
+
+    for x in seq("filter", &args[1])? {
+        if truthy(&apply_fn(args[0].clone(), vec![x.clone()], env.clone())?) {
+            ret.push(x);
         }
-        _ => Err("fn* expected list of symbols as first argument")?
     }
 
-    let mut is_variadic = false;
-    let mut min_args = symbols.len();
-    let mut new_symbols = vec![];
+    Ok(LangVal::list(ret))
+}
 
-    // check variadic
-    for i in 0..symbols.len() {
-        if symbols[i] == "&" {
-            if is_variadic {
-                Err("& only allowed to be used once in function signature")?;
-            }
+// (reduce f coll) or (reduce f init coll), f being called with no arguments if there's nothing to reduce
+fn fn_reduce(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("reduce", &args, 2, Some(3))?;
 
-            is_variadic = true;
-            min_args = i;
-        } else {
-            new_symbols.push(symbols[i].clone());
+    let mut elems = seq("reduce", &args[args.len()-1])?.into_iter();
+    let mut acc = match args.len() {
+        3 => args[1].clone(),
+        _ => match elems.next() {
+            Some(x) => x,
+            None => return apply_fn(args[0].clone(), vec![], env)
         }
-    }
+    };
 
-    if is_variadic {
-        symbols = new_symbols;
+    for x in elems {
+        acc = apply_fn(args[0].clone(), vec![acc, x], env.clone())?;
     }
 
-    let ast = args[1].clone();
-
-    Ok(LangVal::DefinedFunction {
-        symbols,
-        ast: Box::new(ast),
-        env: env.clone(),
-        min_args,
-        is_variadic,
-    })
+    Ok(acc)
 }
 
-fn fn_pr_str(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    let s = args.into_iter().map(|x| {
-        pr_str(&x, true)
-    }).join(" ");
+fn fn_throw(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("throw", &args, 1, Some(1))?;
 
-    Ok(LangVal::String(s))
+    Err(ErrorKind::Thrown(args[0].clone()))?
 }
 
-fn fn_str(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    let s = args.into_iter().map(|x| {
-        pr_str(&x, false)
-    }).join("");
+fn fn_try(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    check_arity("try*", &args, 1, Some(2))?;
+    if args.len() == 1 {
+        return Ok((args[0].clone(), env));
+    }
 
-    Ok(LangVal::String(s))
-}
+    // (catch* e body) or (catch* e trace body) to also get the stack trace
+    let (sym, trace_sym, handler) = match &args[1] {
+        LangVal::List(v, _) if v.len() == 3 || v.len() == 4 => match (&v[0], &v[1], &v[2]) {
+            (LangVal::Symbol(c), LangVal::Symbol(sym), _) if c == "catch*" && v.len() == 3 => {
+                (sym.clone(), None, v[2].clone())
+            }
+            (LangVal::Symbol(c), LangVal::Symbol(sym), LangVal::Symbol(trace)) if c == "catch*" => {
+                (sym.clone(), Some(trace.clone()), v[3].clone())
+            }
+            _ => Err(format!("try* expected a (catch* symbol body) form, got {}", pr_str(&args[1], true)))?
+        },
+        _ => Err(format!("try* expected a (catch* symbol body) form, got {}", pr_str(&args[1], true)))?
+    };
 
-fn fn_prn(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    let s = args.into_iter().map(|x| {
-        pr_str(&x, true)
-    }).join(" ");
+    match eval(args[0].clone(), env.clone()) {
+        Ok(val) => Ok((quoted(val), env)),
+        Err(why) => {
+            let trace = why.trace.iter()
+                .map(|frame| LangVal::String(pr_frame(frame)))
+                .collect();
+
+            // native errors are caught as their message string
+            let caught = match *why.kind {
+                ErrorKind::Thrown(val) => val,
+                kind => LangVal::String(kind.to_string())
+            };
+
+            let env = env_push(Some(env));
+            env_set(&env, &sym, caught);
+            if let Some(trace_sym) = trace_sym {
+                env_set(&env, &trace_sym, LangVal::list(trace));
+            }
 
-    println!("{}", s);
+            Ok((handler, env))
+        }
+    }
+}
 
-    Ok(LangVal::Nil)
+fn fn_list(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    Ok(LangVal::list(args))
 }
 
-fn fn_println(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
-    let s = args.into_iter().map(|x| {
-        pr_str(&x, false)
-    }).join(" ");
+fn fn_list_q(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("list?", &args, 1, Some(1))?;
+    match &args[0] {
+        LangVal::List(_, _) => Ok(LangVal::Boolean(true)),
+        _ => Ok(LangVal::Boolean(false))
+    }
+}
 
-    println!("{}", s);
+fn fn_string_q(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("string?", &args, 1, Some(1))?;
 
-    Ok(LangVal::Nil)
+    Ok(LangVal::Boolean(matches!(args[0], LangVal::String(_))))
 }
 
-pub fn make_core_env() -> Env {
-    let ret = env_push(None);
+fn fn_keyword(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("keyword", &args, 1, Some(1))?;
 
-    // normal functions
-    env_set(&ret, "+", LangVal::Function(add));
-    env_set(&ret, "*", LangVal::Function(multiply));
-    env_set(&ret, "-", LangVal::Function(subtract));
-    env_set(&ret, "/", LangVal::Function(divide));
-    env_set(&ret, "list", LangVal::Function(fn_list));
-    env_set(&ret, "list?", LangVal::Function(fn_list_q));
-    env_set(&ret, "empty?", LangVal::Function(fn_empty_q));
-    env_set(&ret, "count", LangVal::Function(fn_count));
-    env_set(&ret, "=", LangVal::Function(fn_eq));
-    env_set(&ret, ">", LangVal::Function(fn_greater));
-    env_set(&ret, "pr-str", LangVal::Function(fn_pr_str));
-    env_set(&ret, "str", LangVal::Function(fn_str));
-    env_set(&ret, "prn", LangVal::Function(fn_prn));
-    env_set(&ret, "println", LangVal::Function(fn_println));
+    match &args[0] {
+        LangVal::String(s) => Ok(LangVal::keyword(s)),
+        LangVal::Keyword(_) => Ok(args[0].clone()),
+        _ => Err(LangError::type_error("keyword", "a string or keyword", &args[0]))
+    }
+}
 
-    // special functions
-    env_set(&ret, "def!", LangVal::SpecialFunction(fn_def));
-    env_set(&ret, "let*", LangVal::TCOFunction(fn_let));
-    env_set(&ret, "do", LangVal::TCOFunction(fn_do));
-    env_set(&ret, "if", LangVal::TCOFunction(fn_if));
-    env_set(&ret, "fn*", LangVal::SpecialFunction(fn_fn));
+fn fn_keyword_q(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("keyword?", &args, 1, Some(1))?;
 
-    // functions defined using the language itself
-    let defns = vec![
-        // boolean functions
-        "(def! not (fn* (a) (if a false true)))",
-        "(def! or (fn* (a b) (if a true (if b true false))))",
-        "(def! and (fn* (a b) (if a (if b true false) false)))",
-        // comparisons
-        "(def! >= (fn* (a b) (or (= a b) (> a b))))",
-        "(def! < (fn* (a b) (not (>= a b))))",
-        "(def! <= (fn* (a b) (not (> a b))))",
-    ];
+    Ok(LangVal::Boolean(matches!(args[0], LangVal::Keyword(_))))
+}
 
-    for def in defns {
-        eval(reader::read_str(def).unwrap(), ret.clone()).unwrap();
+// name of a keyword or symbol as a string, without the : of keywords
+fn fn_name(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("name", &args, 1, Some(1))?;
+
+    match &args[0] {
+        LangVal::Keyword(k) => Ok(LangVal::String(k.to_string())),
+        LangVal::Symbol(s)|LangVal::String(s) => Ok(LangVal::String(s.clone())),
+        _ => Err(LangError::type_error("name", "a keyword, symbol or string", &args[0]))
     }
+}
 
-    ret
-}
\ No newline at end of file
+// anything seq walks through, so strings count characters and hashmaps entries
+fn fn_empty_q(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("empty?", &args, 1, Some(1))?;
+
+    Ok(LangVal::Boolean(seq_nth("empty?", &args[0], 0)?.is_none()))
+}
+
+fn fn_count(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("count", &args, 1, Some(1))?;
+
+    Ok(LangVal::Integer(seq_len("count", &args[0])? as i64))
+}
+
+// only nil and false are false, everything else counts as true
+fn truthy(val: &LangVal) -> bool {
+    !matches!(val, LangVal::Nil|LangVal::Boolean(false))
+}
+
+fn fn_if(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    check_arity("if", &args, 2, Some(3))?;
+
+    let true_case = args[1].clone();
+    let false_case = if args.len() == 3 {args[2].clone()} else {LangVal::Nil};
+
+    if truthy(&eval(args[0].clone(), env.clone())?) {
+        Ok((true_case, env))
+    } else {
+        Ok((false_case, env))
+    }
+}
+
+// body forms of when and friends as one form to hand back to the TCO loop
+fn body(forms: &[LangVal]) -> LangVal {
+    match forms {
+        [] => LangVal::Nil,
+        [form] => form.clone(),
+        _ => {
+            let mut ret = vec![LangVal::TCOFunction(fn_do)];
+            ret.extend(forms.iter().cloned());
+            LangVal::list(ret)
+        }
+    }
+}
+
+// first false value, or the last value if they're all true. the last form is in tail position
+fn fn_and(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    let last = match args.last() {
+        Some(last) => last.clone(),
+        None => return Ok((LangVal::Boolean(true), env))
+    };
+
+    for arg in &args[..args.len()-1] {
+        let val = eval(arg.clone(), env.clone())?;
+
+        if !truthy(&val) {
+            return Ok((quoted(val), env));
+        }
+    }
+
+    Ok((last, env))
+}
+
+// first true value, or the last value if they're all false
+fn fn_or(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    let last = match args.last() {
+        Some(last) => last.clone(),
+        None => return Ok((LangVal::Nil, env))
+    };
+
+    for arg in &args[..args.len()-1] {
+        let val = eval(arg.clone(), env.clone())?;
+
+        if truthy(&val) {
+            return Ok((quoted(val), env));
+        }
+    }
+
+    Ok((last, env))
+}
+
+// (cond test expr ...) evaluates the expr of the first true test, nil if there is none
+fn fn_cond(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    if !args.len().is_multiple_of(2) {
+        Err("cond expected an even number of tests and expressions")?;
+    }
+
+    for (test, expr) in args.into_iter().tuples() {
+        if truthy(&eval(test, env.clone())?) {
+            return Ok((expr, env));
+        }
+    }
+
+    Ok((LangVal::Nil, env))
+}
+
+fn fn_when(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    check_arity("when", &args, 1, None)?;
+
+    if truthy(&eval(args[0].clone(), env.clone())?) {
+        Ok((body(&args[1..]), env))
+    } else {
+        Ok((LangVal::Nil, env))
+    }
+}
+
+fn fn_unless(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    check_arity("unless", &args, 1, None)?;
+
+    if truthy(&eval(args[0].clone(), env.clone())?) {
+        Ok((LangVal::Nil, env))
+    } else {
+        Ok((body(&args[1..]), env))
+    }
+}
+
+// (case expr value result ... default), values aren't evaluated and a list of them matches any
+// of its elements. with no default and no match it's an error
+fn fn_case(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    check_arity("case", &args, 1, None)?;
+
+    let val = eval(args[0].clone(), env.clone())?;
+    let clauses = &args[1..];
+
+    for (test, result) in clauses.iter().tuples() {
+        let options = match test {
+            LangVal::List(v, _) => v.clone(),
+            _ => vec![test.clone()]
+        };
+
+        for option in options {
+            if val == option {
+                return Ok((result.clone(), env));
+            }
+        }
+    }
+
+    if !clauses.len().is_multiple_of(2) {
+        return Ok((clauses[clauses.len()-1].clone(), env));
+    }
+
+    Err(format!("case has no clause matching {}", pr_str(&val, true)))?
+}
+
+// (if-let [pattern test] then else) is if, with the value of test bound to pattern in then
+fn fn_if_let(args: Vec<LangVal>, env: Env) -> Result<(LangVal, Env)> {
+    check_arity("if-let", &args, 2, Some(3))?;
+
+    let (pattern, test) = match &args[0] {
+        LangVal::Vector(v, _)|LangVal::List(v, _) if v.len() == 2 => (v[0].clone(), v[1].clone()),
+        _ => Err(format!("if-let expected a [name test] binding, got {}", pr_str(&args[0], true)))?
+    };
+
+    let val = eval(test, env.clone())?;
+
+    if truthy(&val) {
+        let inner = env_push(Some(env));
+        destructure("if-let", &pattern, val, &inner)?;
+
+        Ok((args[1].clone(), inner))
+    } else {
+        Ok((args.get(2).cloned().unwrap_or(LangVal::Nil), env))
+    }
+}
+
+// true when every neighbouring pair of args is structurally equal
+fn fn_eq(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("=", &args, 1, None)?;
+
+    Ok(LangVal::Boolean(args.windows(2).all(|pair| pair[0] == pair[1])))
+}
+
+// only values that keep a single cell when copied around have an identity: atoms, interned
+// keywords and functions, compared by that cell. nil and booleans are only ever one value.
+// everything else is a fresh copy, so (identical? [1] [1]) and (identical? "a" "a") are false
+fn identical(a: &LangVal, b: &LangVal) -> bool {
+    match (a, b) {
+        (LangVal::Nil, LangVal::Nil) => true,
+        (LangVal::Boolean(x), LangVal::Boolean(y)) => x == y,
+        (LangVal::Atom(x), LangVal::Atom(y)) => Rc::ptr_eq(x, y),
+        (LangVal::Keyword(x), LangVal::Keyword(y)) => Rc::ptr_eq(x, y),
+        (LangVal::Function(_), LangVal::Function(_))|
+        (LangVal::SpecialFunction(_), LangVal::SpecialFunction(_))|
+        (LangVal::TCOFunction(_), LangVal::TCOFunction(_))|
+        (LangVal::DefinedFunction {..}, LangVal::DefinedFunction {..}) => a == b,
+        _ => false
+    }
+}
+
+fn fn_identical_q(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("identical?", &args, 2, Some(2))?;
+
+    Ok(LangVal::Boolean(identical(&args[0], &args[1])))
+}
+
+// None when a NaN is involved, which isn't smaller, bigger or equal to anything
+fn compare_vals(name: &str, a: &LangVal, b: &LangVal) -> Result<Option<Ordering>> {
+    if let (Some(x), Some(y)) = (Num::from_val(a), Num::from_val(b)) {
+        return Ok(numeric::cmp(&x, &y));
+    }
+
+    match (a, b) {
+        // nil comes before everything
+        (LangVal::Nil, LangVal::Nil) => Ok(Some(Ordering::Equal)),
+        (LangVal::Nil, _) => Ok(Some(Ordering::Less)),
+        (_, LangVal::Nil) => Ok(Some(Ordering::Greater)),
+        (LangVal::Boolean(x), LangVal::Boolean(y)) => Ok(Some(x.cmp(y))),
+        (LangVal::String(x), LangVal::String(y))|
+        (LangVal::Symbol(x), LangVal::Symbol(y)) => Ok(Some(x.cmp(y))),
+        (LangVal::Keyword(x), LangVal::Keyword(y)) => Ok(Some(x.cmp(y))),
+        // element by element, a sequence that runs out first is smaller
+        (LangVal::List(v1, _)|LangVal::Vector(v1, _), LangVal::List(v2, _)|LangVal::Vector(v2, _)) => {
+            for (x, y) in zip(v1, v2) {
+                match compare_vals(name, x, y)? {
+                    Some(Ordering::Equal) => {}
+                    res => return Ok(res)
+                }
+            }
+            Ok(Some(v1.len().cmp(&v2.len())))
+        }
+        _ => Err(LangError::type_error(name, &format!("a value that can be compared to {}", pr_str(a, true)), b))
+    }
+}
+
+// true when every neighbouring pair of args compares as one of the wanted orderings
+fn compare_chain(name: &str, args: &[LangVal], wanted: &[Ordering]) -> Result<LangVal> {
+    check_arity(name, args, 1, None)?;
+
+    for pair in args.windows(2) {
+        match compare_vals(name, &pair[0], &pair[1])? {
+            Some(res) if wanted.contains(&res) => {}
+            _ => return Ok(LangVal::Boolean(false))
+        }
+    }
+
+    Ok(LangVal::Boolean(true))
+}
+
+fn fn_less(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    compare_chain("<", &args, &[Ordering::Less])
+}
+
+fn fn_less_eq(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    compare_chain("<=", &args, &[Ordering::Less, Ordering::Equal])
+}
+
+fn fn_greater(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    compare_chain(">", &args, &[Ordering::Greater])
+}
+
+fn fn_greater_eq(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    compare_chain(">=", &args, &[Ordering::Greater, Ordering::Equal])
+}
+
+fn fn_not_eq(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("not=", &args, 1, None)?;
+
+    Ok(LangVal::Boolean(args.windows(2).any(|pair| pair[0] != pair[1])))
+}
+
+// -1, 0 or 1 like a sort comparator. NaN is put after every other number so the order stays total
+fn fn_compare(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("compare", &args, 2, Some(2))?;
+
+    let is_nan = |val: &LangVal| matches!(val, LangVal::Float(n) if n.is_nan());
+
+    let res = match compare_vals("compare", &args[0], &args[1])? {
+        Some(res) => res,
+        None => is_nan(&args[0]).cmp(&is_nan(&args[1]))
+    };
+
+    Ok(LangVal::Integer(res as i64))
+}
+
+// sections of a parameter list, in the order they have to come in
+#[derive(PartialEq, PartialOrd)]
+enum Params {
+    Required,
+    Optional,
+    Rest,
+    Key
+}
+
+fn param_pattern(param: &LangVal) -> Result<LangVal> {
+    match param {
+        LangVal::Symbol(_)|LangVal::Vector(_, _)|LangVal::Hashmap(_, _) => Ok(param.clone()),
+        _ => Err(format!("fn* expected a symbol or destructuring pattern as parameter, got {}", pr_str(param, true)).into())
+    }
+}
+
+// (name default) or just name, whose default is then nil
+fn param_default(param: &LangVal) -> Result<(LangVal, LangVal)> {
+    match param {
+        LangVal::List(v, _) if v.len() == 2 => Ok((param_pattern(&v[0])?, v[1].clone())),
+        _ => Ok((param_pattern(param)?, LangVal::Nil))
+    }
+}
+
+// parses a parameter list like (a [b c] &optional d (e 1) & rest) or (a &key f (g 2))
+fn fn_clause(params: &LangVal, ast: LangVal) -> Result<Clause> {
+    let params = match params {
+        LangVal::List(v, _)|LangVal::Vector(v, _) => v,
+        _ => Err(format!("fn* expected a list of parameters, got {}", pr_str(params, true)))?
+    };
+
+    let mut clause = Clause {
+        params: vec![],
+        optional: vec![],
+        rest: None,
+        keys: vec![],
+        ast
+    };
+    let mut section = Params::Required;
+
+    for param in params {
+        let next = match param {
+            LangVal::Symbol(s) if s == "&optional" => Some(Params::Optional),
+            LangVal::Symbol(s) if s == "&" => Some(Params::Rest),
+            LangVal::Symbol(s) if s == "&key" => Some(Params::Key),
+            _ => None
+        };
+
+        if let Some(next) = next {
+            if section >= next || section > Params::Optional {
+                Err(format!("{} is out of place, parameters go in the order required, &optional, then & or &key",
+                            pr_str(param, true)))?;
+            }
+
+            section = next;
+            continue;
+        }
+
+        match section {
+            Params::Required => clause.params.push(param_pattern(param)?),
+            Params::Optional => clause.optional.push(param_default(param)?),
+            Params::Rest => {
+                if clause.rest.is_some() {
+                    Err("& has to be followed by exactly one parameter")?;
+                }
+
+                clause.rest = Some(param_pattern(param)?);
+            }
+            Params::Key => match param_default(param)? {
+                (LangVal::Symbol(s), default) => clause.keys.push((s, default)),
+                (other, _) => Err(format!("fn* expected a symbol after &key, got {}", pr_str(&other, true)))?
+            }
+        }
+    }
+
+    if section == Params::Rest && clause.rest.is_none() {
+        Err("& has to be followed by exactly one parameter")?;
+    }
+
+    Ok(clause)
+}
+
+// (fn* params body) or (fn* ([params] body...) ([params] body...)) with a clause per arity
+fn fn_fn(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("fn*", &args, 1, None)?;
+
+    // clauses have their parameters in a vector, like ([a b] body). a single body can't start
+    // with a vector since vectors can't be called, so (fn* ([a] b) (f a b)) is one clause taking
+    // a destructured first parameter, while (fn* ([a] b) ([a b] c)) has two clauses
+    let is_clause = |arg: &LangVal| {
+        matches!(arg, LangVal::List(v, _) if matches!(v.first(), Some(LangVal::Vector(_, _))))
+    };
+
+    let clauses = if args.len() == 2 && !args.iter().all(is_clause) {
+        vec![fn_clause(&args[0], args[1].clone())?]
+    } else {
+        args.iter().map(|arg| match arg {
+            LangVal::List(v, _) if is_clause(arg) => fn_clause(&v[0], body(&v[1..])),
+            _ => Err(format!("fn* expected a ([params] body) clause with a vector of parameters, got {}", pr_str(arg, true)).into())
+        }).collect::<Result<Vec<_>>>()?
+    };
+
+    // the clause for a number of arguments has to be unambiguous. a variadic clause can take counts
+    // a fixed one takes too, like ([x] 1) ([x & more] 2), since the fixed one is picked first
+    for (i, a) in clauses.iter().enumerate() {
+        for b in &clauses[i+1..] {
+            match (a.arity(), b.arity()) {
+                ((_, None), (_, None)) => Err("fn* has more than one variadic clause")?,
+                ((min_a, Some(max_a)), (min_b, Some(max_b))) if min_a <= max_b && min_b <= max_a => {
+                    let n = min_a.max(min_b);
+                    Err(format!("fn* has more than one clause taking {} argument{}", n, if n == 1 { "" } else { "s" }))?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(LangVal::DefinedFunction {
+        clauses: Rc::new(clauses),
+        env: env.clone(),
+        is_macro: false,
+        name: None,
+        meta: Meta::default()
+    })
+}
+
+fn fn_pr_str(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    let s = args.into_iter().map(|x| {
+        pr_str(&x, true)
+    }).join(" ");
+
+    Ok(LangVal::String(s))
+}
+
+fn fn_str(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    let s = args.into_iter().map(|x| {
+        pr_str(&x, false)
+    }).join("");
+
+    Ok(LangVal::String(s))
+}
+
+fn fn_prn(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    let s = args.into_iter().map(|x| {
+        pr_str(&x, true)
+    }).join(" ");
+
+    println!("{}", s);
+
+    Ok(LangVal::Nil)
+}
+
+fn fn_println(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    let s = args.into_iter().map(|x| {
+        pr_str(&x, false)
+    }).join(" ");
+
+    println!("{}", s);
+
+    Ok(LangVal::Nil)
+}
+
+fn fn_read_string(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("read-string", &args, 1, Some(1))?;
+
+    match &args[0] {
+        LangVal::String(s) => reader::read_str(s),
+        _ => Err(LangError::type_error("read-string", "a string", &args[0]))?
+    }
+}
+
+fn fn_eval(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("eval", &args, 1, Some(1))?;
+
+    eval(args[0].clone(), env_root(&env))
+}
+
+fn fn_slurp(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("slurp", &args, 1, Some(1))?;
+
+    match &args[0] {
+        LangVal::String(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|why| format!("Could not read {}: {}", path, why))?;
+
+            Ok(LangVal::String(text))
+        }
+        _ => Err(LangError::type_error("slurp", "a string", &args[0]))?
+    }
+}
+
+// evaluates every form of the file in the root environment, stopping at the first error
+pub fn load_file(path: &str, env: &Env) -> Result<LangVal> {
+    let file = std::fs::File::open(path)
+        .map_err(|why| format!("Could not read {}: {}", path, why))?;
+    let env = env_root(env);
+    let mut input = std::io::BufReader::new(file);
+
+    // a #! first line lets scripts be run directly, it is read as an empty line to keep line numbers
+    let mut first = String::new();
+    input.read_line(&mut first).map_err(|why| format!("Could not read {}: {}", path, why))?;
+    if first.starts_with("#!") {
+        first = "\n".to_string();
+    }
+
+    for val in reader::read_forms(path, std::io::Cursor::new(first).chain(input)) {
+        eval(val?, env.clone())?;
+    }
+
+    Ok(LangVal::Nil)
+}
+
+fn fn_load_file(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("load-file", &args, 1, Some(1))?;
+
+    match &args[0] {
+        LangVal::String(path) => load_file(path, &env),
+        _ => Err(LangError::type_error("load-file", "a string", &args[0]))?
+    }
+}
+
+fn fn_atom(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("atom", &args, 1, Some(1))?;
+
+    Ok(LangVal::Atom(Rc::new(Atom {
+        value: RefCell::new(args[0].clone()),
+        watches: RefCell::new(vec![])
+    })))
+}
+
+fn fn_atom_q(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("atom?", &args, 1, Some(1))?;
+
+    Ok(LangVal::Boolean(matches!(args[0], LangVal::Atom(_))))
+}
+
+fn try_atom(name: &str, val: &LangVal) -> Result<Rc<Atom>> {
+    match val {
+        LangVal::Atom(atom) => Ok(atom.clone()),
+        _ => Err(LangError::type_error(name, "an atom", val))
+    }
+}
+
+// stores the new value and lets the watches know about it
+fn atom_set(atom: &Rc<Atom>, new: LangVal, env: &Env) -> Result<()> {
+    let old = atom.value.replace(new.clone());
+
+    // cloned so watch functions can add or remove watches themselves
+    let watches = atom.watches.borrow().clone();
+
+    for (key, func) in watches {
+        apply_fn(func, vec![key, LangVal::Atom(atom.clone()), old.clone(), new.clone()], env.clone())?;
+    }
+
+    Ok(())
+}
+
+fn fn_deref(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("deref", &args, 1, Some(1))?;
+
+    let atom = try_atom("deref", &args[0])?;
+    let val = atom.value.borrow().clone();
+
+    Ok(val)
+}
+
+fn fn_reset(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("reset!", &args, 2, Some(2))?;
+
+    atom_set(&try_atom("reset!", &args[0])?, args[1].clone(), &env)?;
+
+    Ok(args[1].clone())
+}
+
+fn fn_swap(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("swap!", &args, 2, None)?;
+
+    let atom = try_atom("swap!", &args[0])?;
+
+    // the borrow has to end before calling the function, which might deref the atom too
+    let old = atom.value.borrow().clone();
+    let mut func_args = vec![old];
+    func_args.extend(args[2..].iter().cloned());
+
+    let new = apply_fn(args[1].clone(), func_args, env.clone())?;
+    atom_set(&atom, new.clone(), &env)?;
+
+    Ok(new)
+}
+
+fn fn_compare_and_set(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("compare-and-set!", &args, 3, Some(3))?;
+
+    let atom = try_atom("compare-and-set!", &args[0])?;
+    let cur = atom.value.borrow().clone();
+
+    if cur == args[1] {
+        atom_set(&atom, args[2].clone(), &env)?;
+        Ok(LangVal::Boolean(true))
+    } else {
+        Ok(LangVal::Boolean(false))
+    }
+}
+
+fn fn_add_watch(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("add-watch", &args, 3, Some(3))?;
+
+    let atom = try_atom("add-watch", &args[0])?;
+
+    // a watch added again under the same key replaces the old one
+    fn_remove_watch(args[..2].to_vec(), env)?;
+    atom.watches.borrow_mut().push((args[1].clone(), args[2].clone()));
+
+    Ok(args[0].clone())
+}
+
+fn fn_remove_watch(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("remove-watch", &args, 2, Some(2))?;
+
+    let atom = try_atom("remove-watch", &args[0])?;
+    atom.watches.borrow_mut().retain(|(key, _)| *key != args[1]);
+
+    Ok(args[0].clone())
+}
+
+fn fn_with_meta(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("with-meta", &args, 2, Some(2))?;
+
+    if !matches!(args[1], LangVal::Hashmap(_, _)|LangVal::Nil) {
+        Err(LangError::type_error("with-meta", "a hashmap or nil as metadata", &args[1]))?;
+    }
+
+    let mut val = args[0].clone();
+
+    match val.meta_mut() {
+        Some(meta) => {
+            meta.value = match &args[1] {
+                LangVal::Nil => None,
+                m => Some(Rc::new(m.clone()))
+            };
+        }
+        None => Err(LangError::type_error("with-meta", "a collection or defined function", &args[0]))?
+    }
+
+    Ok(val)
+}
+
+fn fn_meta(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("meta", &args, 1, Some(1))?;
+
+    match args[0].meta().and_then(|meta| meta.value.as_deref()) {
+        Some(val) => Ok(val.clone()),
+        None => Ok(LangVal::Nil)
+    }
+}
+
+// (vary-meta obj f & args) is (with-meta obj (f (meta obj) & args))
+fn fn_vary_meta(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("vary-meta", &args, 2, None)?;
+
+    let mut func_args = vec![fn_meta(args[..1].to_vec(), env.clone())?];
+    func_args.extend(args[2..].iter().cloned());
+
+    let meta = apply_fn(args[1].clone(), func_args, env.clone())?;
+
+    fn_with_meta(vec![args[0].clone(), meta], env)
+}
+
+// nil works as an empty map, like it works as an empty list
+fn try_hashmap(name: &str, val: &LangVal) -> Result<Hashmap> {
+    match val {
+        LangVal::Hashmap(mp, _) => Ok(mp.clone()),
+        LangVal::Nil => Ok(Hashmap::default()),
+        _ => Err(LangError::type_error(name, "a hashmap", val))
+    }
+}
+
+fn map_key(name: &str, val: &LangVal) -> Result<MapKey> {
+    MapKey::from_val(val).ok_or_else(|| LangError::type_error(name, "a hashable value as key", val))
+}
+
+// adds the key value pairs in args to mp, replacing keys that are already there
+fn map_insert(name: &str, mut mp: Hashmap, args: &[LangVal]) -> Result<LangVal> {
+    if !args.len().is_multiple_of(2) {
+        Err(format!("{} expected an even number of keys and values, got {}", name, args.len()))?;
+    }
+
+    for (k, v) in args.iter().tuples() {
+        mp.insert(map_key(name, k)?, v.clone());
+    }
+
+    Ok(LangVal::hashmap(mp))
+}
+
+fn fn_hash_map(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    map_insert("hash-map", Hashmap::default(), &args)
+}
+
+fn fn_map_q(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("map?", &args, 1, Some(1))?;
+
+    Ok(LangVal::Boolean(matches!(args[0], LangVal::Hashmap(_, _))))
+}
+
+// (get m key default), default being nil when not given
+fn fn_get(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("get", &args, 2, Some(3))?;
+
+    let mp = try_hashmap("get", &args[0])?;
+    let default = args.get(2).cloned().unwrap_or(LangVal::Nil);
+
+    // values that can't be keys are never in the map
+    match MapKey::from_val(&args[1]).and_then(|k| mp.get(&k).cloned()) {
+        Some(val) => Ok(val),
+        None => Ok(default)
+    }
+}
+
+fn fn_assoc(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("assoc", &args, 3, None)?;
+
+    map_insert("assoc", try_hashmap("assoc", &args[0])?, &args[1..])
+}
+
+fn fn_dissoc(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("dissoc", &args, 1, None)?;
+
+    let mut mp = try_hashmap("dissoc", &args[0])?;
+
+    for k in &args[1..] {
+        if let Some(k) = MapKey::from_val(k) {
+            mp.remove(&k);
+        }
+    }
+
+    Ok(LangVal::hashmap(mp))
+}
+
+fn fn_contains_q(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("contains?", &args, 2, Some(2))?;
+
+    let mp = try_hashmap("contains?", &args[0])?;
+
+    Ok(LangVal::Boolean(MapKey::from_val(&args[1]).is_some_and(|k| mp.contains_key(&k))))
+}
+
+fn fn_keys(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("keys", &args, 1, Some(1))?;
+
+    Ok(LangVal::list(try_hashmap("keys", &args[0])?.keys().map(MapKey::to_val).collect()))
+}
+
+fn fn_vals(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("vals", &args, 1, Some(1))?;
+
+    Ok(LangVal::list(try_hashmap("vals", &args[0])?.into_values().collect()))
+}
+
+// later maps win when the same key is in more than one, nil if every map is nil
+fn fn_merge(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    if args.iter().all(|arg| matches!(arg, LangVal::Nil)) {
+        return Ok(LangVal::Nil);
+    }
+
+    let mut ret = Hashmap::default();
+
+    for arg in &args {
+        ret.extend(try_hashmap("merge", arg)?);
+    }
+
+    Ok(LangVal::hashmap(ret))
+}
+
+fn fn_select_keys(args: Vec<LangVal>, _: Env) -> Result<LangVal> {
+    check_arity("select-keys", &args, 2, Some(2))?;
+
+    let mp = try_hashmap("select-keys", &args[0])?;
+    let mut ret = Hashmap::default();
+
+    match &args[1] {
+        LangVal::List(v, _)|LangVal::Vector(v, _) => {
+            for k in v {
+                if let Some((k, val)) = MapKey::from_val(k).and_then(|k| mp.get_key_value(&k)) {
+                    ret.insert(k.clone(), val.clone());
+                }
+            }
+        }
+        LangVal::Nil => {}
+        _ => Err(LangError::type_error("select-keys", "a list of keys", &args[1]))?
+    }
+
+    Ok(LangVal::hashmap(ret))
+}
+
+// (update m key f & args) sets key to (f old-value & args), old-value being nil if key isn't there
+fn fn_update(args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    check_arity("update", &args, 3, None)?;
+
+    let mut mp = try_hashmap("update", &args[0])?;
+    let key = map_key("update", &args[1])?;
+
+    let mut func_args = vec![mp.get(&key).cloned().unwrap_or(LangVal::Nil)];
+    func_args.extend(args[3..].iter().cloned());
+
+    let new = apply_fn(args[2].clone(), func_args, env)?;
+    mp.insert(key, new);
+
+    Ok(LangVal::hashmap(mp))
+}
+
+pub fn make_core_env() -> Env {
+    let ret = env_push(None);
+
+    // normal functions
+    env_set(&ret, "+", LangVal::Function(add));
+    env_set(&ret, "*", LangVal::Function(multiply));
+    env_set(&ret, "-", LangVal::Function(subtract));
+    env_set(&ret, "/", LangVal::Function(divide));
+    env_set(&ret, "numerator", LangVal::Function(fn_numerator));
+    env_set(&ret, "denominator", LangVal::Function(fn_denominator));
+    env_set(&ret, "list", LangVal::Function(fn_list));
+    env_set(&ret, "list?", LangVal::Function(fn_list_q));
+    env_set(&ret, "string?", LangVal::Function(fn_string_q));
+    env_set(&ret, "keyword", LangVal::Function(fn_keyword));
+    env_set(&ret, "keyword?", LangVal::Function(fn_keyword_q));
+    env_set(&ret, "name", LangVal::Function(fn_name));
+    env_set(&ret, "empty?", LangVal::Function(fn_empty_q));
+    env_set(&ret, "count", LangVal::Function(fn_count));
+    env_set(&ret, "=", LangVal::Function(fn_eq));
+    env_set(&ret, "identical?", LangVal::Function(fn_identical_q));
+    env_set(&ret, "not=", LangVal::Function(fn_not_eq));
+    env_set(&ret, "<", LangVal::Function(fn_less));
+    env_set(&ret, "<=", LangVal::Function(fn_less_eq));
+    env_set(&ret, ">", LangVal::Function(fn_greater));
+    env_set(&ret, ">=", LangVal::Function(fn_greater_eq));
+    env_set(&ret, "compare", LangVal::Function(fn_compare));
+    env_set(&ret, "pr-str", LangVal::Function(fn_pr_str));
+    env_set(&ret, "str", LangVal::Function(fn_str));
+    env_set(&ret, "prn", LangVal::Function(fn_prn));
+    env_set(&ret, "println", LangVal::Function(fn_println));
+    env_set(&ret, "read-string", LangVal::Function(fn_read_string));
+    env_set(&ret, "eval", LangVal::Function(fn_eval));
+    env_set(&ret, "slurp", LangVal::Function(fn_slurp));
+    env_set(&ret, "load-file", LangVal::Function(fn_load_file));
+    env_set(&ret, "atom", LangVal::Function(fn_atom));
+    env_set(&ret, "atom?", LangVal::Function(fn_atom_q));
+    env_set(&ret, "deref", LangVal::Function(fn_deref));
+    env_set(&ret, "reset!", LangVal::Function(fn_reset));
+    env_set(&ret, "swap!", LangVal::Function(fn_swap));
+    env_set(&ret, "compare-and-set!", LangVal::Function(fn_compare_and_set));
+    env_set(&ret, "add-watch", LangVal::Function(fn_add_watch));
+    env_set(&ret, "remove-watch", LangVal::Function(fn_remove_watch));
+    env_set(&ret, "with-meta", LangVal::Function(fn_with_meta));
+    env_set(&ret, "meta", LangVal::Function(fn_meta));
+    env_set(&ret, "vary-meta", LangVal::Function(fn_vary_meta));
+    env_set(&ret, "hash-map", LangVal::Function(fn_hash_map));
+    env_set(&ret, "map?", LangVal::Function(fn_map_q));
+    env_set(&ret, "get", LangVal::Function(fn_get));
+    env_set(&ret, "assoc", LangVal::Function(fn_assoc));
+    env_set(&ret, "dissoc", LangVal::Function(fn_dissoc));
+    env_set(&ret, "contains?", LangVal::Function(fn_contains_q));
+    env_set(&ret, "keys", LangVal::Function(fn_keys));
+    env_set(&ret, "vals", LangVal::Function(fn_vals));
+    env_set(&ret, "merge", LangVal::Function(fn_merge));
+    env_set(&ret, "select-keys", LangVal::Function(fn_select_keys));
+    env_set(&ret, "update", LangVal::Function(fn_update));
+    env_set(&ret, "throw", LangVal::Function(fn_throw));
+    env_set(&ret, "cons", LangVal::Function(fn_cons));
+    env_set(&ret, "concat", LangVal::Function(fn_concat));
+    env_set(&ret, "vec", LangVal::Function(fn_vec));
+    env_set(&ret, "conj", LangVal::Function(fn_conj));
+    env_set(&ret, "first", LangVal::Function(fn_first));
+    env_set(&ret, "rest", LangVal::Function(fn_rest));
+    env_set(&ret, "nth", LangVal::Function(fn_nth));
+    env_set(&ret, "last", LangVal::Function(fn_last));
+    env_set(&ret, "take", LangVal::Function(fn_take));
+    env_set(&ret, "drop", LangVal::Function(fn_drop));
+    env_set(&ret, "reverse", LangVal::Function(fn_reverse));
+    env_set(&ret, "range", LangVal::Function(fn_range));
+    env_set(&ret, "apply", LangVal::Function(fn_apply));
+    env_set(&ret, "map", LangVal::Function(fn_map));
+    env_set(&ret, "filter", LangVal::Function(fn_filter));
+    env_set(&ret, "reduce", LangVal::Function(fn_reduce));
+
+    // special functions
+    env_set(&ret, "def!", LangVal::SpecialFunction(fn_def));
+    env_set(&ret, "defmacro!", LangVal::SpecialFunction(fn_defmacro));
+    env_set(&ret, "macroexpand-1", LangVal::SpecialFunction(fn_macroexpand_1));
+    env_set(&ret, "macroexpand", LangVal::SpecialFunction(fn_macroexpand));
+    env_set(&ret, "let*", LangVal::TCOFunction(fn_let));
+    env_set(&ret, "do", LangVal::TCOFunction(fn_do));
+    env_set(&ret, "if", LangVal::TCOFunction(fn_if));
+    env_set(&ret, "and", LangVal::TCOFunction(fn_and));
+    env_set(&ret, "or", LangVal::TCOFunction(fn_or));
+    env_set(&ret, "cond", LangVal::TCOFunction(fn_cond));
+    env_set(&ret, "when", LangVal::TCOFunction(fn_when));
+    env_set(&ret, "unless", LangVal::TCOFunction(fn_unless));
+    env_set(&ret, "case", LangVal::TCOFunction(fn_case));
+    env_set(&ret, "if-let", LangVal::TCOFunction(fn_if_let));
+    env_set(&ret, "try*", LangVal::TCOFunction(fn_try));
+    env_set(&ret, "fn*", LangVal::SpecialFunction(fn_fn));
+    env_set(&ret, "quote", LangVal::SpecialFunction(fn_quote));
+    env_set(&ret, "quasiquote", LangVal::TCOFunction(fn_quasiquote));
+    env_set(&ret, "quasiquoteexpand", LangVal::SpecialFunction(fn_quasiquoteexpand));
+    env_set(&ret, "unquote", LangVal::SpecialFunction(fn_unquote));
+    env_set(&ret, "splice-unquote", LangVal::SpecialFunction(fn_splice_unquote));
+
+    // functions defined using the language itself
+    let defns = vec![
+        // boolean functions
+        "(def! not (fn* (a) (if a false true)))",
+    ];
+
+    for def in defns {
+        eval(reader::read_str(def).unwrap(), ret.clone()).unwrap();
+    }
+
+    ret
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Source, env_get};
+
+    fn run(src: &str) -> Result<LangVal> {
+        let env = make_core_env();
+        let mut ret = LangVal::Nil;
+
+        for form in reader::read_all(Source::new("<test>", src))? {
+            ret = eval(form, env.clone())?;
+        }
+
+        Ok(ret)
+    }
+
+    fn run_str(src: &str) -> String {
+        match run(src) {
+            Ok(val) => pr_str(&val, true),
+            Err(err) => err.to_string()
+        }
+    }
+
+    #[test]
+    fn fn_destructured_first_param_is_one_clause() {
+        assert_eq!(run_str("(def! ops {:inc (fn* (x) (+ x 1))}) ((fn* ([op] x) ((get ops op) x)) [:inc] 5)"), "6");
+    }
+
+    #[test]
+    fn fn_clauses_dispatch_on_argument_count() {
+        let def = "(def! f (fn* ([] 0) ([x] (list 1 x)) ([x y & more] (list 2 more))))";
+
+        assert_eq!(run_str(&format!("{} (f)", def)), "0");
+        assert_eq!(run_str(&format!("{} (f :a)", def)), "(1 :a)");
+        assert_eq!(run_str(&format!("{} (f :a :b :c)", def)), "(2 (:c))");
+    }
+
+    #[test]
+    fn fixed_clauses_come_before_variadic_ones() {
+        assert_eq!(run_str("(def! f (fn* ([x] 1) ([x & more] more))) (list (f 1) (f 1 2 3))"), "(1 (2 3))");
+        assert_eq!(run_str("(def! f (fn* ([& xs] xs) ([] 0))) (list (f) (f 1))"), "(0 (1))");
+    }
+
+    #[test]
+    fn fn_optional_and_key_params() {
+        assert_eq!(run_str("((fn* (a &optional (b (+ a 1)) c) (list a b c)) 1)"), "(1 2 nil)");
+        assert_eq!(run_str("((fn* (a &key (b 10) c) (list a b c)) 1 :c 3)"), "(1 10 3)");
+        assert_eq!(run_str("(def! h (fn* (a &key b) b)) (h 1 :z 2)"), "h got unknown keyword argument :z");
+    }
+
+    #[test]
+    fn fn_clause_errors() {
+        assert_eq!(run_str("(fn* ([a] 1) ([b] 2))"), "fn* has more than one clause taking 1 argument");
+        assert_eq!(run_str("(fn* ([a &optional b] 1) ([a b c] 2) ([a b] 3))"), "fn* has more than one clause taking 2 arguments");
+        assert_eq!(run_str("(fn* ([a & b] 1) ([& c] 2))"), "fn* has more than one variadic clause");
+        assert_eq!(run_str("(fn* (a &key b &optional c) 1)"),
+                   "&optional is out of place, parameters go in the order required, &optional, then & or &key");
+        assert_eq!(run_str("(fn* (&) 1)"), "& has to be followed by exactly one parameter");
+        assert_eq!(run_str("(def! f (fn* ([x] 1) ([x y] 2) ([a b c d & e] 3))) (f 1 2 3)"),
+                   "f expected 1, 2 or at least 4 arguments, got 3");
+    }
+
+    #[test]
+    fn identity_only_for_shared_values() {
+        assert_eq!(run_str("(def! a (atom 1)) (list (identical? a a) (identical? a (atom 1)))"), "(true false)");
+        assert_eq!(run_str("(list (identical? :k :k) (identical? nil nil) (identical? [1] [1]) (identical? \"a\" \"a\"))"),
+                   "(true true false false)");
+        assert_eq!(run_str("(def! f (fn* (x) x)) (list (identical? f f) (= f f) (identical? f (fn* (x) x)) (= f (fn* (x) x)))"),
+                   "(true true false false)");
+    }
+
+    #[test]
+    fn range_always_ends() {
+        assert_eq!(run_str("(range 1e16 (+ 1e16 3) 1)"), "(10000000000000000.0)");
+        assert_eq!(run_str("(range 0 ##Inf)"), "range can't take an infinite bound or step");
+        assert_eq!(run_str("(range 0 1 1/3)"), "(0 1/3 2/3)");
+    }
+
+    #[test]
+    fn count_and_empty_take_the_same_values() {
+        assert_eq!(run_str("(list (count nil) (count \"abc\") (count {:a 1 :b 2}) (count [1 2]))"), "(0 3 2 2)");
+        assert_eq!(run_str("(list (empty? nil) (empty? \"\") (empty? {}) (empty? '(1)))"), "(true true true false)");
+        assert_eq!(run_str("(count 1)"), "count expected a list, vector, hashmap, string or nil, got integer: 1");
+    }
+
+    #[test]
+    fn quasiquote_splices_into_collections() {
+        assert_eq!(run_str("(def! xs (list 1 2)) `(a ~@xs ~xs)"), "(a 1 2 (1 2))");
+        assert_eq!(run_str("(def! xs (list 1 2)) `[0 ~@xs 3]"), "[0 1 2 3]");
+        assert_eq!(run_str("(def! xs (list 1 2)) (= `{:a ~(+ 1 2) :b [~@xs]} {:a 3 :b [1 2]})"), "true");
+        assert_eq!(run_str("'(a ~b)"), "(a (unquote b))");
+    }
+
+    #[test]
+    fn macros_expand_before_evaluation() {
+        let def = "(defmacro! unless (fn* (c a b) `(if ~c ~b ~a))) (defmacro! twice (fn* (x) `(unless false ~x ~x)))";
+
+        assert_eq!(run_str(&format!("{} (twice 1)", def)), "1");
+        assert_eq!(run_str(&format!("{} (macroexpand-1 (twice y))", def)), "(unless false y y)");
+        assert_eq!(run_str(&format!("{} (macroexpand (twice y))", def)), "(if false y y)");
+        assert_eq!(run_str(&format!("{} (macroexpand (+ 1 2))", def)), "(+ 1 2)");
+        // the arguments get to the macro unevaluated
+        assert_eq!(run_str(&format!("{} (unless true (undefined) 2)", def)), "2");
+    }
+
+    #[test]
+    fn try_catches_thrown_and_native_errors() {
+        assert_eq!(run_str("(try* (throw {:code 2}) (catch* e (get e :code)))"), "2");
+        assert_eq!(run_str("(try* (nth [1] 5) (catch* e e))"), "\"nth index 5 out of range for a collection of 1\"");
+        assert_eq!(run_str("(try* (undefined) (catch* e e))"), "\"Symbol undefined not found\"");
+        assert_eq!(run_str("(try* (+ 1 2) (catch* e :never))"), "3");
+        assert_eq!(run_str("(throw [1 2])"), "Uncaught exception: [1 2]");
+        // throwing from a handler goes on to the outer try*
+        assert_eq!(run_str("(try* (try* (throw 1) (catch* e (throw (+ e 1)))) (catch* e e))"), "2");
+    }
+
+    #[test]
+    fn malformed_forms_are_not_type_errors() {
+        let kind = |src: &str| run(src).err().map(|err| *err.kind);
+
+        assert!(matches!(kind("(let* [[a &] [1]] a)"), Some(ErrorKind::Other(_))));
+        assert!(matches!(kind("(let* [[a :as] [1]] a)"), Some(ErrorKind::Other(_))));
+        assert!(matches!(kind("(try* 1 (catch e 2))"), Some(ErrorKind::Other(_))));
+        assert!(matches!(kind("(fn* (1) 2)"), Some(ErrorKind::Other(_))));
+        assert!(matches!(kind("(+ 1 :a)"), Some(ErrorKind::Type {..})));
+        assert!(matches!(kind("(let* [[a] 1] a)"), Some(ErrorKind::Type {..})));
+        assert_eq!(run_str("(let* [[a &] [1]] a)"), "let* expected & to be followed by a pattern in [a &]");
+    }
+
+    fn run_error(src: &str) -> String {
+        match run(src) {
+            Ok(val) => pr_str(&val, true),
+            Err(err) => crate::printer::pr_error(&err)
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_code() {
+        assert_eq!(run_error("(+ 1\n   (foo))"), "<test>:2:4: Symbol foo not found\n       (foo))\n       ^");
+        assert_eq!(run_error("(def! f (fn* (x)\n  (+ x y)))\n(f 1)"),
+                   "<test>:2:3: Symbol y not found\n      (+ x y)))\n      ^\n  in (f 1) at <test>:3:1");
+        assert_eq!(run_error("(+ 1 2))"), "<test>:1:8: Unexpected ')'\n    (+ 1 2))\n           ^");
+    }
+
+    #[test]
+    fn traces_summarize_big_arguments() {
+        let trace = |src: &str| run(src).err().map(|err| err.trace.iter().map(pr_frame).collect::<Vec<_>>());
+
+        assert_eq!(trace("(def! f (fn* (xs s) (throw 1))) (f (range 100) (str (range 30)))"),
+                   Some(vec!["(f (0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 1...) at <test>:1:33".to_string()]));
+        assert_eq!(trace("(def! f (fn* (m) (throw 1))) (f {:a [1 2]})"), Some(vec!["(f {:a [1 2]}) at <test>:1:30".to_string()]));
+    }
+
+    #[test]
+    fn only_scripts_skip_a_shebang_line() {
+        let path = std::env::temp_dir().join(format!("shebang-{}.lisp", std::process::id()));
+        std::fs::write(&path, "#!/usr/bin/env rust\n(def! x 1)\n(foo)\n").unwrap();
+
+        let env = make_core_env();
+        let err = load_file(path.to_str().unwrap(), &env).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pr_str(&env_get(&env, &"x".to_string()).unwrap(), true), "1");
+        assert_eq!(err.span.map(|span| span.line), Some(3));
+        assert_eq!(run_str("(read-string \"#!x\")"), "#!x");
+    }
+
+    #[test]
+    fn atoms_holding_themselves_print() {
+        assert_eq!(run_str("(def! a (atom nil)) (reset! a a)"), "(atom ...)");
+        assert_eq!(run_str("(def! a (atom 1)) (swap! a (fn* (x) [x a])) a"), "(atom [1 ...])");
+        assert_eq!(run_str("(def! a (atom 1)) (atom [a a])"), "(atom [(atom 1) (atom 1)])");
+    }
+
+    #[test]
+    fn atom_watches_see_every_change() {
+        let def = "(def! a (atom 1)) (def! log (atom [])) \
+                   (add-watch a :log (fn* (k r old new) (swap! log conj [k old new (= r a)])))";
+
+        assert_eq!(run_str(&format!("{} (swap! a + 2) (reset! a 5) (compare-and-set! a 5 6) (compare-and-set! a 0 7) @log", def)),
+                   "[[:log 1 3 true] [:log 3 5 true] [:log 5 6 true]]");
+        // adding under the same key replaces the watch, and removed watches aren't called
+        assert_eq!(run_str(&format!("{} (add-watch a :log (fn* (& _) (swap! log conj :new))) (reset! a 2) \
+                                     (remove-watch a :log) (reset! a 3) @log", def)),
+                   "[:new]");
+    }
+
+    #[test]
+    fn hashmap_api() {
+        let def = "(def! m {:a 1 \"b\" 2})";
+        let check = |src: &str| run_str(&format!("{} {}", def, src));
+
+        assert_eq!(check("(list (map? m) (map? [1]) (= (hash-map :a 1 \"b\" 2) m))"), "(true false true)");
+        assert_eq!(check("(list (get m :a) (get m :z) (get m :z 0) (get nil :a))"), "(1 nil 0 nil)");
+        assert_eq!(check("(= (assoc m :c 3 :a 0) {:a 0 \"b\" 2 :c 3})"), "true");
+        assert_eq!(check("(list (dissoc m :a \"b\") (contains? m \"b\") (contains? m :z) (contains? {:n nil} :n))"),
+                   "({} true false true)");
+        assert_eq!(check("(list (keys {:a 1}) (vals {:a 1}) (apply + (vals m)) (count (keys m)))"), "((:a) (1) 3 2)");
+        assert_eq!(check("(= (merge {:a 1} {:a 2 :b 3} nil) {:a 2 :b 3})"), "true");
+        assert_eq!(check("(list (select-keys m [:a :z]) (= (update m :a + 10) {:a 11 \"b\" 2}))"), "({:a 1} true)");
+        assert_eq!(check("(list (= {:a [1 2]} {:a '(1 2)}) (= m {:a 1}) (= m (assoc m :a 1)))"), "(true false true)");
+    }
+
+    #[test]
+    fn sequence_accessors() {
+        assert_eq!(run_str("(list (first \"abc\") (nth \"abc\" 1) (last \"abc\") (first nil) (last []) (nth [1] 5 :d))"),
+                   "(\"a\" \"b\" \"c\" nil nil :d)");
+        assert_eq!(run_str("(list (first {:a 1}) (last '(1 2 3)) (nth [1 2] 0) (count \"h\u{e9}llo\"))"), "([:a 1] 3 1 5)");
+        assert_eq!(run_str("(nth [1] -1)"), "nth index -1 out of range for a collection of 1");
+        assert_eq!(run_str("(first 1)"), "first expected a list, vector, hashmap, string or nil, got integer: 1");
+    }
+
+    #[test]
+    fn traces_look_the_same_through_apply() {
+        let def = "(def! g (fn* (x) (throw x))) (def! f (fn* (x) (g x)))";
+        let trace = |src: &str| run(&format!("{} {}", def, src)).err()
+            .map(|err| err.trace.iter().map(|frame| (frame.name.clone(), frame.tail_calls)).collect::<Vec<_>>());
+
+        let expected = Some(vec![(Some("g".to_string()), 1)]);
+        assert_eq!(trace("(f 1)"), expected);
+        assert_eq!(trace("(map f [1])"), expected);
+        assert_eq!(trace("(apply f [1])"), expected);
+    }
+
+    #[test]
+    fn and_or_short_circuit() {
+        assert_eq!(run_str("(list (and) (and 1 2) (and 1 nil (undefined)) (and false 2))"), "(true 2 nil false)");
+        assert_eq!(run_str("(list (or) (or nil 2) (or 1 (undefined)) (or nil false))"), "(nil 2 1 false)");
+        assert_eq!(run_str("(def! x nil) (and x (first x))"), "nil");
+    }
+
+    #[test]
+    fn conditionals_are_in_tail_position() {
+        let forms = ["(and true (g n))", "(or false (g n))", "(cond false 1 :else (g n))", "(when true (g n))",
+                     "(unless false (g n))", "(case n 2 1 (g n))", "(if-let [x n] (g x) 1)"];
+
+        for form in &forms {
+            let src = format!("(def! g (fn* (x) (throw x))) (def! f (fn* (n) {})) (f 1)", form);
+            let trace = run(&src).err()
+                .map(|err| err.trace.iter().map(|frame| (frame.name.clone(), frame.tail_calls)).collect::<Vec<_>>());
+
+            assert_eq!(trace, Some(vec![(Some("g".to_string()), 1)]), "{}", form);
+        }
+
+        assert_eq!(run_str("(def! down (fn* (n) (cond (= n 0) :done :else (and true (down (- n 1)))))) (down 10000)"),
+                   ":done");
+    }
+
+    #[test]
+    fn variadic_comparisons() {
+        assert_eq!(run_str("(list (< 1 2 3) (< 1 3 2) (<= 1 1 2) (> 3 2 1) (>= 3 3 4) (< 1) (< 1/2 0.6 1))"),
+                   "(true false true true false true true)");
+        assert_eq!(run_str("(list (not= 1 2) (not= 1 1.0) (not= [1] '(1)))"), "(true false false)");
+        assert_eq!(run_str("(list (< \"a\" \"b\") (> \"b\" \"a\" \"A\") (<= :a :a :b))"), "(true true true)");
+        assert_eq!(run_str("(list (compare \"a\" \"b\") (compare :b :a) (compare [1 2] [1 3]) (compare 2 2.0) (compare 1 ##NaN))"),
+                   "(-1 1 -1 0 -1)");
+        assert_eq!(run_str("(< 1 \"a\")"), "< expected a value that can be compared to 1, got string: \"a\"");
+    }
+
+    #[test]
+    fn sequential_destructuring() {
+        assert_eq!(run_str("(let* [[a [b c] & more :as all] [1 [2 3] 4 5]] (list a b c more all))"), "(1 2 3 (4 5) [1 [2 3] 4 5])");
+        assert_eq!(run_str("(let* [[a b] '(1)] (list a b))"), "(1 nil)");
+        assert_eq!(run_str("(let* [[a b & c] \"xyz\"] (list a b c))"), "(\"x\" \"y\" (\"z\"))");
+        assert_eq!(run_str("(def! [x y] [1 2]) (+ x y)"), "3");
+    }
+
+    #[test]
+    fn associative_destructuring() {
+        let m = "{:a 1 \"b\" 2 'c 3 :d {:e [4 5]}}";
+
+        assert_eq!(run_str(&format!("(let* [{{:keys [a z] :strs [b] :syms [c] :as m}} {}] (list a z b c (get m :a)))", m)),
+                   "(1 nil 2 3 1)");
+        assert_eq!(run_str(&format!("(let* [{{x :a {{[e1 e2] :e}} :d}} {}] (list x e1 e2))", m)), "(1 4 5)");
+        assert_eq!(run_str("(let* [{:keys [a b] :or {b (+ a 1)}} {:a 1}] (list a b))"), "(1 2)");
+        assert_eq!(run_str("(let* [{:keys [a]} nil] a)"), "nil");
+        assert_eq!(run_str("(if-let [{:keys [a]} {:a 1}] a 2)"), "1");
+    }
+
+    #[test]
+    fn keyword_arguments_destructure_into_a_map() {
+        let def = "(def! f (fn* [a & {:keys [z] :or {z 0} :as opts}] (list a z opts)))";
+
+        assert_eq!(run_str(&format!("{} (f 1 :z 3)", def)), "(1 3 {:z 3})");
+        assert_eq!(run_str(&format!("{} (f 1)", def)), "(1 0 {})");
+        assert_eq!(run_str("(def! g (fn* [& {:keys [z]}] z)) (g :z)"),
+                   "g expected key value pairs to destructure into {:keys [z]}, got an odd number of values");
+    }
+
+    #[test]
+    fn destructuring_shape_errors() {
+        assert_eq!(run_str("(let* [[a] 1] a)"), "let* expected a sequence to destructure into [a], got integer: 1");
+        assert_eq!(run_str("(let* [{:keys [a]} [1]] a)"), "let* expected a hashmap to destructure into {:keys [a]}, got vector: [1]");
+        assert_eq!(run_str("(let* [{:keys a} {}] a)"), "let* expected a vector of symbols after :keys, got a");
+        assert_eq!(run_str("(let* [{:keys [:a]} {}] a)"), "let* expected a symbol in :keys, got :a");
+        assert_eq!(run_str("(let* [{:or 1} {}] 1)"), "let* expected a hashmap of defaults after :or, got 1");
+        assert_eq!(run_str("(let* [1 2] 1)"), "let* expected a symbol, vector or hashmap as binding, got 1");
+    }
+}
@@ -1,42 +1,81 @@
-use crate::types::{LangVal, Result, Hashmap, Env, env_push, env_set, env_get};
-use itertools::zip;
+use crate::types::{LangVal, LangError, ErrorKind, Result, Hashmap, MapKey, Clause, Env, Span, Frame, env_push, env_set, env_get, seq};
+use itertools::Itertools;
+use crate::printer::{pr_str, frame_args};
 
 pub fn eval(start_val: LangVal, start_env: Env) -> Result<LangVal> {
+    eval_call(start_val, start_env, None)
+}
+
+// evaluates the body of the call in frame, if any, which tail calls then replace like any other
+fn eval_call(start_val: LangVal, start_env: Env, call: Option<Frame>) -> Result<LangVal> {
+    // innermost read form we got to, so errors can point at it
+    let mut span = None;
+    // function call we are currently in, tail calls replace it instead of stacking up
+    let mut frame = call;
+
+    eval_form(start_val, start_env, &mut span, &mut frame).map_err(|err| {
+        let mut err = err.at(&span);
+        err.trace.extend(frame);
+        err
+    })
+}
+
+fn eval_form(start_val: LangVal, start_env: Env,
+             span: &mut Option<Span>, frame: &mut Option<Frame>) -> Result<LangVal> {
 
     let mut val = start_val;
     let mut env = start_env;
 
     loop { // loop for TCO
         match val {
-            LangVal::List(to_eval) => {
-                if to_eval.len() == 0 {
-                    return Ok(LangVal::List(vec![]));
+            LangVal::List(to_eval, meta) => {
+                if meta.span.is_some() {
+                    *span = meta.span;
+                }
+
+                if to_eval.is_empty() {
+                    return Ok(LangVal::list(vec![]));
                 } else {
                     let func = eval(to_eval[0].clone(), env.clone())?;
                     let args = to_eval[1..].to_vec();
 
                     match func { // need to know if we should evaluate args or not
                         LangVal::Function(f) => {
-                            let args = eval_ast(LangVal::List(args), env.clone())?.try_list().unwrap();
+                            let args = eval_ast(LangVal::list(args), env.clone())?.try_list().unwrap();
                             return f(args, env.clone());
                         }
                         LangVal::SpecialFunction(f) => {
                             return f(args, env.clone());
                         }
                         LangVal::DefinedFunction {
-                            symbols,
-                            ast,
+                            clauses,
                             env: other_env,
-                            min_args,
-                            is_variadic,
+                            is_macro,
+                            name,
+                            ..
                         } => {
-                            let args = eval_ast(LangVal::List(args),
+                            if is_macro {
+                                // macros get their arguments raw and we evaluate whatever they expand to
+                                let (new_ast, new_env) = eval_defined(args, &name, &clauses, &other_env)?;
+
+                                val = eval(new_ast, new_env)?;
+
+                                continue;
+                            }
+
+                            let args = eval_ast(LangVal::list(args),
                                 env.clone())?.try_list().unwrap();
 
-                            let (new_ast, new_env) = eval_defined(args, symbols, ast,
-                                                                  min_args, is_variadic,
-                                                                  &mut other_env.clone())?;
+                            let call = Frame {
+                                name: name.clone(),
+                                args: frame_args(&args),
+                                span: span.clone(),
+                                tail_calls: frame.as_ref().map_or(0, |f| f.tail_calls + 1)
+                            };
 
+                            let (new_ast, new_env) = eval_defined(args, &name, &clauses, &other_env)?;
+
+                            *frame = Some(call);
                             val = new_ast;
                             env = new_env;
 
@@ -51,11 +90,17 @@ pub fn eval(start_val: LangVal, start_env: Env) -> Result<LangVal> {
                             continue; // loop instead of recursing, which lets us save some space
                         }
                         _ => {
-                            Err("Expected function as first argument")?;
+                            Err(LangError::type_error("eval", "a function as first element of list", &func))?;
                         }
                     }
                 }
             }
+            LangVal::WithSpecial((name, inner)) => {
+                // reader shorthand like 'x is the same as the (quote x) call
+                val = LangVal::list(vec![LangVal::Symbol(name), (*inner).clone()]);
+
+                continue;
+            }
             _ => {
                 return eval_ast(val, env);
             }
@@ -72,62 +117,278 @@ pub fn eval_ast(val: LangVal, env: Env) -> Result<LangVal> {
         LangVal::Symbol(s) => {
             env_get(&env, &s)
         }
-        LangVal::List(elems) => {
+        LangVal::List(elems, _) => {
             let mut ret = vec![];
 
             for i in elems {
                 ret.push(eval(i, env.clone())?);
             }
 
-            Ok(LangVal::List(ret))
+            Ok(LangVal::list(ret))
         }
-        LangVal::Vector(elems) => {
+        LangVal::Vector(elems, meta) => {
             let mut ret = vec![];
 
             for i in elems {
-                ret.push(eval(i, env.clone())?);
+                ret.push(eval(i, env.clone()).map_err(|err| err.at(&meta.span))?);
             }
 
-            Ok(LangVal::Vector(ret))
+            Ok(LangVal::vector(ret))
         }
-        LangVal::Hashmap(mp) => {
+        LangVal::Hashmap(mp, meta) => {
             let mut ret = Hashmap::default();
 
+            // keys are evaluated too, so {(keyword "a") 1} is keyed by :a
             for (k, val) in mp {
-                ret.insert(k, eval(val, env.clone())?);
+                let k = eval(k.to_val(), env.clone()).map_err(|err| err.at(&meta.span))?;
+                let k = MapKey::from_val(&k)
+                    .ok_or_else(|| LangError::type_error("hashmap", "a hashable value as key", &k).at(&meta.span))?;
+
+                ret.insert(k, eval(val, env.clone()).map_err(|err| err.at(&meta.span))?);
             }
 
-            Ok(LangVal::Hashmap(ret))
+            Ok(LangVal::hashmap(ret))
         }
         _ => Ok(val)
     }
 }
 
-fn eval_defined(args: Vec<LangVal>, symbols: Vec<String>, ast: Box<LangVal>,
-                min_args: usize, is_variadic: bool, env: &Env)
--> Result<(LangVal, Env)> {
-    if is_variadic {
-        if args.len() < min_args {
-            Err(format!("function expected at least {} arguments, got {}", min_args, args.len()))?;
+// calls a function value with arguments that are already evaluated, for builtins that take
+// callbacks and for embedders. special forms and macros work on unevaluated code, so they can't
+// be called this way
+pub fn apply_fn(func: LangVal, args: Vec<LangVal>, env: Env) -> Result<LangVal> {
+    match func {
+        LangVal::Function(f) => f(args, env),
+        LangVal::DefinedFunction {
+            clauses,
+            env: other_env,
+            is_macro: false,
+            name,
+            ..
+        } => {
+            let call = Frame {
+                name: name.clone(),
+                args: frame_args(&args),
+                span: None,
+                tail_calls: 0
+            };
+
+            let (new_ast, new_env) = eval_defined(args, &name, &clauses, &other_env)?;
+
+            eval_call(new_ast, new_env, Some(call))
+        }
+        LangVal::SpecialFunction(_)|LangVal::TCOFunction(_) => {
+            Err(LangError::type_error("apply", "a function rather than a special form", &func))
         }
-    } else {
-        if args.len() != min_args {
-            Err(format!("function expected {} arguments, got {}", min_args, args.len()))?;
+        _ => Err(LangError::type_error("apply", "a function", &func))
+    }
+}
+
+// expands ast once if it is a call to a macro, None otherwise
+pub fn macroexpand_1(ast: &LangVal, env: &Env) -> Result<Option<LangVal>> {
+    if let LangVal::List(elems, _) = ast {
+        if let Some(LangVal::Symbol(s)) = elems.first() {
+            if let Ok(LangVal::DefinedFunction {
+                clauses,
+                env: other_env,
+                is_macro: true,
+                name,
+                ..
+            }) = env_get(env, s) {
+                let (new_ast, new_env) = eval_defined(elems[1..].to_vec(), &name, &clauses, &other_env)?;
+
+                return Ok(Some(eval(new_ast, new_env)?));
+            }
         }
     }
 
+    Ok(None)
+}
+
+// keeps expanding ast until it is no longer a macro call
+pub fn macroexpand(ast: LangVal, env: &Env) -> Result<LangVal> {
+    let mut ast = ast;
+
+    while let Some(expanded) = macroexpand_1(&ast, env)? {
+        ast = expanded;
+    }
+
+    Ok(ast)
+}
+
+// binds args to the parameters of the clause taking that many of them, returning its body and the
+// environment to evaluate it in
+fn eval_defined(args: Vec<LangVal>, name: &Option<String>, clauses: &[Clause], env: &Env)
+-> Result<(LangVal, Env)> {
+    let name = name.as_deref().unwrap_or("function");
+
+    // a fixed arity clause is picked over a variadic one taking the same number of arguments
+    let clause = clauses.iter()
+        .filter(|clause| clause.accepts(args.len()))
+        .min_by_key(|clause| clause.arity().1.is_none());
+
+    let clause = match clause {
+        Some(clause) => clause,
+        None => Err(ErrorKind::Arity {
+            name: name.to_string(),
+            arities: clauses.iter().map(Clause::arity).collect(),
+            got: args.len()
+        })?
+    };
+
     let env = env_push(Some(env.clone()));
+    let mut args = args.into_iter();
+
+    for param in &clause.params {
+        destructure(name, param, args.next().unwrap(), &env)?;
+    }
+
+    // defaults are evaluated in the new environment, so they can use the parameters before them
+    for (param, default) in &clause.optional {
+        let val = match args.next() {
+            Some(val) => val,
+            None => eval(default.clone(), env.clone())?
+        };
 
-    if is_variadic {
-        for i in 0..(min_args) {
-            env_set(&env, &symbols[i], args[i].clone());
+        destructure(name, param, val, &env)?;
+    }
+
+    if let Some(rest) = &clause.rest {
+        destructure(name, rest, LangVal::list(args.by_ref().collect()), &env)?;
+    }
+
+    if !clause.keys.is_empty() {
+        let given: Vec<LangVal> = args.collect();
+
+        if !given.len().is_multiple_of(2) {
+            Err(format!("{} expected keyword arguments as :key value pairs, got an odd number of values", name))?;
         }
-        env_set(&env, &symbols[min_args], LangVal::List(args[(min_args)..].to_vec()));
-    } else {
-        for (i, j) in zip(symbols, args) {
-            env_set(&env, &i, j);
+
+        let mut vals = std::collections::HashMap::new();
+
+        for (k, v) in given.into_iter().tuples() {
+            match &k {
+                LangVal::Keyword(key) if clause.keys.iter().any(|(s, _)| **s == **key) => {
+                    vals.insert(key.to_string(), v);
+                }
+                _ => Err(format!("{} got unknown keyword argument {}", name, pr_str(&k, true)))?
+            }
+        }
+
+        for (s, default) in &clause.keys {
+            let val = match vals.remove(s) {
+                Some(val) => val,
+                None => eval(default.clone(), env.clone())?
+            };
+
+            env_set(&env, s, val);
         }
     }
 
-    Ok((*(ast.clone()), env))
+    Ok((clause.ast.clone(), env))
+}
+
+// binds the symbols in pattern to the matching parts of val. patterns are a symbol, a sequential
+// [a b & rest :as all] or an associative {a :a :keys [b] :strs [c] :syms [d] :or {b 1} :as m}.
+// associative ones also take a list of key value pairs, so [& {:keys [a]}] destructures keyword
+// arguments. parts that aren't there are bound to nil, or to their :or default which is evaluated in env
+pub fn destructure(name: &str, pattern: &LangVal, val: LangVal, env: &Env) -> Result<()> {
+    match pattern {
+        LangVal::Symbol(s) => {
+            env_set(env, s, val);
+            Ok(())
+        }
+        LangVal::Vector(pats, _)|LangVal::List(pats, _) => {
+            let elems = match val {
+                LangVal::List(_, _)|LangVal::Vector(_, _)|LangVal::String(_)|LangVal::Nil => seq(name, &val)?,
+                _ => Err(LangError::type_error(name, &format!("a sequence to destructure into {}", pr_str(pattern, true)), &val))?
+            };
+            let mut elems = elems.into_iter();
+            let mut pats = pats.iter();
+
+            while let Some(pat) = pats.next() {
+                match pat {
+                    LangVal::Symbol(s) if s == "&" => {
+                        let rest = pats.next().ok_or_else(|| format!("{} expected & to be followed by a pattern in {}", name, pr_str(pattern, true)))?;
+                        destructure(name, rest, LangVal::list(elems.by_ref().collect()), env)?;
+                    }
+                    LangVal::Keyword(k) if &**k == "as" => {
+                        let all = pats.next().ok_or_else(|| format!("{} expected :as to be followed by a pattern in {}", name, pr_str(pattern, true)))?;
+                        destructure(name, all, val.clone(), env)?;
+                    }
+                    _ => destructure(name, pat, elems.next().unwrap_or(LangVal::Nil), env)?
+                }
+            }
+
+            Ok(())
+        }
+        LangVal::Hashmap(pats, _) => {
+            let mp = match &val {
+                LangVal::Hashmap(mp, _) => mp.clone(),
+                LangVal::Nil => Hashmap::default(),
+                // rest arguments like (:a 1 :b 2), for [x & {:keys [a b]}]
+                LangVal::List(v, _) => {
+                    if !v.len().is_multiple_of(2) {
+                        Err(format!("{} expected key value pairs to destructure into {}, got an odd number of values",
+                                    name, pr_str(pattern, true)))?;
+                    }
+
+                    v.iter().tuples().map(|(k, v)| {
+                        let k = MapKey::from_val(k).ok_or_else(|| LangError::type_error(name, "a hashable value as key", k))?;
+                        Ok((k, v.clone()))
+                    }).collect::<Result<Hashmap>>()?
+                }
+                _ => Err(LangError::type_error(name, &format!("a hashmap to destructure into {}", pr_str(pattern, true)), &val))?
+            };
+            let defaults = match pats.get(&MapKey(LangVal::keyword("or"))) {
+                Some(LangVal::Hashmap(defaults, _)) => defaults.clone(),
+                Some(other) => Err(format!("{} expected a hashmap of defaults after :or, got {}", name, pr_str(other, true)))?,
+                None => Hashmap::default()
+            };
+
+            // value under key, falling back on the default of the symbol it's bound to
+            let lookup = |key: LangVal, pat: &LangVal| -> Result<LangVal> {
+                match (mp.get(&MapKey(key)), defaults.get(&MapKey(pat.clone()))) {
+                    (Some(val), _) => Ok(val.clone()),
+                    (None, Some(default)) => eval(default.clone(), env.clone()),
+                    (None, None) => Ok(LangVal::Nil)
+                }
+            };
+
+            for (k, v) in pats {
+                match &k.0 {
+                    LangVal::Keyword(opt) if matches!(&**opt, "keys"|"strs"|"syms") => {
+                        let syms = match v {
+                            LangVal::Vector(syms, _)|LangVal::List(syms, _) => syms,
+                            _ => Err(format!("{} expected a vector of symbols after :{}, got {}", name, opt, pr_str(v, true)))?
+                        };
+
+                        for sym in syms {
+                            let s = match sym {
+                                LangVal::Symbol(s) => s,
+                                _ => Err(format!("{} expected a symbol in :{}, got {}", name, opt, pr_str(sym, true)))?
+                            };
+                            let key = match &**opt {
+                                "keys" => LangVal::keyword(s),
+                                "strs" => LangVal::String(s.clone()),
+                                _ => LangVal::Symbol(s.clone())
+                            };
+
+                            env_set(env, s, lookup(key, sym)?);
+                        }
+                    }
+                    // keyword arguments are bound as the hashmap they make up
+                    LangVal::Keyword(opt) if &**opt == "as" => match &val {
+                        LangVal::List(_, _) => destructure(name, v, LangVal::hashmap(mp.clone()), env)?,
+                        _ => destructure(name, v, val.clone(), env)?
+                    },
+                    LangVal::Keyword(opt) if &**opt == "or" => {}
+                    pat => destructure(name, pat, lookup(v.clone(), pat)?, env)?
+                }
+            }
+
+            Ok(())
+        }
+        _ => Err(format!("{} expected a symbol, vector or hashmap as binding, got {}", name, pr_str(pattern, true)).into())
+    }
 }
@@ -6,43 +6,80 @@ mod types;
 mod printer;
 mod eval;
 mod core;
+mod numeric;
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-
+use std::process;
+use types::LangVal;
 
 fn main() {
+    let core_env = core::make_core_env();
+
+    // rust script.lisp arg1 arg2 runs the script with *ARGV* set to (arg1 arg2)
+    let args: Vec<String> = std::env::args().collect();
+    let script_args = args.iter().skip(2).map(|arg| LangVal::String(arg.clone())).collect();
+    types::env_set(&core_env, "*ARGV*", LangVal::list(script_args));
+
+    if let Some(path) = args.get(1) {
+        if let Err(why) = core::load_file(path, &core_env) {
+            eprintln!("Error: {}", printer::pr_error(&why));
+            process::exit(1);
+        }
+        return;
+    }
+
     // main input/output loop
     let mut rl = Editor::<()>::new();
 
-    let core_env = core::make_core_env();
+    // lines of a form that isn't finished yet
+    let mut pending = String::new();
 
     loop {
-        let readline = rl.readline("user> ");
+        let readline = rl.readline(if pending.is_empty() { "user> " } else { "  ... " });
 
         match readline {
             Ok(line) => {
-                if line.len() == 0 {
+                let line = line.trim_end_matches(['\n', '\r']); // piped input keeps its line endings
+
+                if line.is_empty() && pending.is_empty() {
                     continue;
                 }
 
-                match reader::read_str(line.as_str()) {
-                    Ok(val) => {
-                        match eval::eval(val, core_env.clone()) {
-                            Ok(res) => {
-                                printer::print_val(&res);
-                            }
-                            Err(why) => {
-                                println!("Error during evaluation: {:?}", why);
+                pending.push_str(line);
+                pending.push('\n');
+
+                match reader::read_all(types::Source::new("<repl>", &pending)) {
+                    Ok(vals) => {
+                        for val in vals {
+                            match eval::eval(val, core_env.clone()) {
+                                Ok(res) => {
+                                    printer::print_val(&res);
+                                }
+                                Err(why) => {
+                                    println!("Error during evaluation: {}", printer::pr_error(&why));
+                                    break;
+                                }
                             }
                         }
                     }
                     Err(why) => {
-                        println!("Error while parsing: {:?}", why);
+                        if let types::ErrorKind::Incomplete(_) = *why.kind {
+                            continue; // keep reading lines until the form is closed
+                        }
+                        println!("Error while parsing: {}", printer::pr_error(&why));
                     }
                 }
+
+                pending.clear();
+            }
+            Err(ReadlineError::Interrupted) => {
+                // ctrl-c throws away a half typed form, and exits otherwise
+                if pending.is_empty() {
+                    break;
+                }
+                pending.clear();
             }
-            Err(ReadlineError::Interrupted) => break, // also exit on ctrl-c
             Err(ReadlineError::Eof) => break,
             Err(err) => {
                 println!("Input error: {:?}", err);
@@ -0,0 +1,161 @@
+use crate::types::{LangVal, Result};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{ToPrimitive, Zero};
+use std::cmp::Ordering;
+
+// a number taken out of a LangVal, so arithmetic doesn't have to care which variant it came from
+#[derive(Clone)]
+pub enum Num {
+    Int(i64),
+    Big(BigInt),
+    Ratio(BigRational),
+    Float(f64)
+}
+
+impl Num {
+    pub fn from_val(val: &LangVal) -> Option<Num> {
+        match val {
+            LangVal::Integer(n) => Some(Num::Int(*n)),
+            LangVal::BigInteger(n) => Some(Num::Big(n.clone())),
+            LangVal::Ratio(n) => Some(Num::Ratio(n.clone())),
+            LangVal::Float(n) => Some(Num::Float(*n)),
+            _ => None
+        }
+    }
+
+    // big integers that fit back into an i64 are shrunk and whole ratios become integers,
+    // so equal numbers always have the same variant
+    pub fn into_val(self) -> LangVal {
+        match self {
+            Num::Int(n) => LangVal::Integer(n),
+            Num::Big(n) => match n.to_i64() {
+                Some(n) => LangVal::Integer(n),
+                None => LangVal::BigInteger(n)
+            },
+            Num::Ratio(n) => {
+                if n.is_integer() {
+                    Num::Big(n.to_integer()).into_val()
+                } else {
+                    LangVal::Ratio(n)
+                }
+            }
+            Num::Float(n) => LangVal::Float(n)
+        }
+    }
+
+    fn to_big(&self) -> BigInt {
+        match self {
+            Num::Int(n) => BigInt::from(*n),
+            Num::Big(n) => n.clone(),
+            Num::Ratio(n) => n.to_integer(),
+            Num::Float(n) => BigInt::from(*n as i64)
+        }
+    }
+
+    fn to_ratio(&self) -> BigRational {
+        match self {
+            Num::Ratio(n) => n.clone(),
+            _ => BigRational::from_integer(self.to_big())
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Num::Int(n) => *n as f64,
+            Num::Big(n) => n.to_f64().unwrap_or(f64::NAN),
+            Num::Ratio(n) => n.to_f64().unwrap_or(f64::NAN),
+            Num::Float(n) => *n
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Num::Int(n) => *n == 0,
+            Num::Big(n) => n.is_zero(),
+            Num::Ratio(n) => n.is_zero(),
+            Num::Float(n) => *n == 0.0
+        }
+    }
+}
+
+// floats win over ratios, which win over integers, and i64 results that overflow are redone with big integers
+fn binary(a: Num, b: Num,
+          int_op: fn(i64, i64) -> Option<i64>,
+          big_op: fn(BigInt, BigInt) -> BigInt,
+          ratio_op: fn(BigRational, BigRational) -> BigRational,
+          float_op: fn(f64, f64) -> f64) -> Num {
+    match (a, b) {
+        (Num::Float(x), y) => Num::Float(float_op(x, y.to_f64())),
+        (x, Num::Float(y)) => Num::Float(float_op(x.to_f64(), y)),
+        (x @ Num::Ratio(_), y)|(x, y @ Num::Ratio(_)) => Num::Ratio(ratio_op(x.to_ratio(), y.to_ratio())),
+        (Num::Int(x), Num::Int(y)) => match int_op(x, y) {
+            Some(n) => Num::Int(n),
+            None => Num::Big(big_op(BigInt::from(x), BigInt::from(y)))
+        },
+        (x, y) => Num::Big(big_op(x.to_big(), y.to_big()))
+    }
+}
+
+pub fn add(a: Num, b: Num) -> Num {
+    binary(a, b, i64::checked_add, |x, y| x + y, |x, y| x + y, |x, y| x + y)
+}
+
+pub fn sub(a: Num, b: Num) -> Num {
+    binary(a, b, i64::checked_sub, |x, y| x - y, |x, y| x - y, |x, y| x - y)
+}
+
+pub fn mul(a: Num, b: Num) -> Num {
+    binary(a, b, i64::checked_mul, |x, y| x * y, |x, y| x * y, |x, y| x * y)
+}
+
+// exact unless a float is involved, integers that don't divide evenly give a ratio
+pub fn div(a: Num, b: Num) -> Result<Num> {
+    if b.is_zero() {
+        Err("Division by 0")?;
+    }
+
+    match (&a, &b) {
+        (Num::Float(_), _)|(_, Num::Float(_)) => Ok(Num::Float(a.to_f64() / b.to_f64())),
+        _ => Ok(Num::Ratio(a.to_ratio() / b.to_ratio()))
+    }
+}
+
+pub fn neg(a: Num) -> Num {
+    sub(Num::Int(0), a)
+}
+
+// None when a NaN is involved
+pub fn cmp(a: &Num, b: &Num) -> Option<Ordering> {
+    match (a, b) {
+        (Num::Float(x), Num::Float(y)) => x.partial_cmp(y),
+        (Num::Float(x), y) => cmp_float(*x, y),
+        (x, Num::Float(y)) => cmp_float(*y, x).map(Ordering::reverse),
+        (Num::Ratio(_), _)|(_, Num::Ratio(_)) => Some(a.to_ratio().cmp(&b.to_ratio())),
+        (Num::Int(x), Num::Int(y)) => Some(x.cmp(y)),
+        _ => Some(a.to_big().cmp(&b.to_big()))
+    }
+}
+
+// compares by the exact value the float holds rather than rounding the other number to a float,
+// so 1/10 isn't equal to both 0.1 and a ratio 0.1 is equal to, keeping comparisons transitive
+fn cmp_float(x: f64, y: &Num) -> Option<Ordering> {
+    if x.is_nan() {
+        None
+    } else if x.is_infinite() {
+        Some(if x > 0.0 { Ordering::Greater } else { Ordering::Less })
+    } else {
+        Some(BigRational::from_float(x)?.cmp(&y.to_ratio()))
+    }
+}
+
+// parts of an exact number, integers being over 1
+pub fn numerator_denominator(a: &Num) -> Option<(BigInt, BigInt)> {
+    match a {
+        Num::Float(_) => None,
+        _ => {
+            let ratio = a.to_ratio();
+            Some((ratio.numer().clone(), ratio.denom().clone()))
+        }
+    }
+}
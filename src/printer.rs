@@ -1,4 +1,9 @@
-use crate::types::LangVal;
+use crate::types::{LangVal, LangError, Frame, Hashmap, Atom};
+use std::rc::Rc;
+use std::cell::RefCell;
+
+// characters of arguments shown per frame of a stack trace
+const FRAME_ARGS: usize = 40;
 
 pub fn print_val(val: &LangVal) {
     println!("{}", pr_str(val, true));
@@ -7,7 +12,7 @@ pub fn print_val(val: &LangVal) {
 pub fn pr_str(val: &LangVal, readable: bool) -> String {
 
     let fmt = |vals: &Vec<LangVal>| -> String {
-        vals.into_iter()
+        vals.iter()
             .map(|x| {pr_str(x, readable)})
             .collect::<Vec<String>>()
             .join(" ")
@@ -20,37 +25,67 @@ pub fn pr_str(val: &LangVal, readable: bool) -> String {
         LangVal::Boolean(b) => {
             if *b { "true".to_string() } else { "false".to_string() }
         }
-        LangVal::List(vals) => {
+        LangVal::List(vals, _) => {
             format!("({})", fmt(vals))
         }
-        LangVal::Vector(vals) => {
+        LangVal::Vector(vals, _) => {
             format!("[{}]", fmt(vals))
         }
-        LangVal::Number(num) => {
-            format!("{}", num)
+        LangVal::Integer(num) => {
+            num.to_string()
+        }
+        LangVal::BigInteger(num) => {
+            num.to_string()
+        }
+        LangVal::Ratio(num) => {
+            num.to_string()
+        }
+        LangVal::Float(num) => {
+            pr_float(*num)
         }
         LangVal::String(str) => {
-            if str.starts_with("\u{29e}") {
-                format!(":{}", &str[2..]) // "\u{29e}" takes up 2 chars, not 1
-            } else if readable {
+            if readable {
                 format!("\"{}\"", escape_str(str))
             } else {
                 str.clone()
             }
         }
-        LangVal::Hashmap(mp) => {
-            format!("{{{}}}", mp.into_iter().map(|(k, v)| {
+        LangVal::Keyword(name) => {
+            format!(":{}", name)
+        }
+        LangVal::Hashmap(mp, _) => {
+            format!("{{{}}}", mp.iter().map(|(k, v)| {
                 format!("{} {}",
-                        pr_str(&LangVal::String(k.clone()), readable),
+                        pr_str(&k.to_val(), readable),
                         pr_str(v, readable))
             }).collect::<Vec<String>>().join(" "))
         }
+        LangVal::DefinedFunction {is_macro: true, ..} => {
+            "<macro>".to_string()
+        }
         LangVal::Function(_)|
         LangVal::SpecialFunction(_)|
         LangVal::TCOFunction(_)|
-        LangVal::DefinedFunction {symbols: _, ast: _, env: _, is_variadic: _, min_args: _} => {
+        LangVal::DefinedFunction {..} => {
             "<function>".to_string()
         }
+        LangVal::Atom(atom) => {
+            thread_local! {
+                static PRINTING: RefCell<Vec<*const Atom>> = const { RefCell::new(vec![]) };
+            }
+
+            // an atom can end up holding itself, which is printed as ... instead of going around forever
+            let ptr = Rc::as_ptr(atom);
+            if PRINTING.with(|printing| printing.borrow().contains(&ptr)) {
+                return "...".to_string();
+            }
+
+            PRINTING.with(|printing| printing.borrow_mut().push(ptr));
+            let ret = format!("(atom {})", pr_str(&atom.value.borrow(), readable));
+            PRINTING.with(|printing| printing.borrow_mut().pop());
+
+            ret
+        }
         LangVal::WithSpecial((name, val)) => {
             format!("({} {})", name, pr_str(val, readable))
         }
@@ -58,6 +93,22 @@ pub fn pr_str(val: &LangVal, readable: bool) -> String {
     }
 }
 
+// prints floats the way the reader reads them back, using exponents for very big or small ones
+// and always with a . or exponent so they don't read back as integers
+fn pr_float(num: f64) -> String {
+    if num.is_nan() {
+        "##NaN".to_string()
+    } else if num.is_infinite() {
+        if num > 0.0 { "##Inf".to_string() } else { "##-Inf".to_string() }
+    } else if num != 0.0 && (num.abs() >= 1e21 || num.abs() < 1e-7) {
+        format!("{:e}", num)
+    } else if num.fract() == 0.0 {
+        format!("{:.1}", num)
+    } else {
+        format!("{}", num)
+    }
+}
+
 fn escape_str(s: &str) -> String {
     s.chars()
         .map(|c| match c {
@@ -68,4 +119,102 @@ fn escape_str(s: &str) -> String {
         })
         .collect::<Vec<String>>()
         .join("")
-}
\ No newline at end of file
+}
+// copy of the arguments of a call that is enough for pr_frame, so calls don't copy big
+// collections in full just in case they fail. every value prints as at least a character and a
+// space, so keeping FRAME_ARGS values keeps the start pr_frame shows
+pub fn frame_args(args: &[LangVal]) -> Vec<LangVal> {
+    let mut budget = FRAME_ARGS;
+
+    summarize(args, &mut budget)
+}
+
+fn summarize(vals: &[LangVal], budget: &mut usize) -> Vec<LangVal> {
+    let mut ret = vec![];
+
+    for val in vals {
+        if *budget == 0 {
+            break;
+        }
+        ret.push(summary(val, budget));
+    }
+
+    ret
+}
+
+fn summary(val: &LangVal, budget: &mut usize) -> LangVal {
+    *budget -= 1;
+
+    match val {
+        LangVal::List(v, _) => LangVal::list(summarize(v, budget)),
+        LangVal::Vector(v, _) => LangVal::vector(summarize(v, budget)),
+        LangVal::Hashmap(mp, _) => {
+            let mut ret = Hashmap::default();
+
+            for (k, v) in mp {
+                if *budget == 0 {
+                    break;
+                }
+                ret.insert(k.clone(), summary(v, budget));
+            }
+
+            LangVal::hashmap(ret)
+        }
+        LangVal::String(s) => LangVal::String(s.chars().take(FRAME_ARGS).collect()),
+        _ => val.clone()
+    }
+}
+
+// one line of a stack trace, like (f 1 "a") at file:1:5
+pub fn pr_frame(frame: &Frame) -> String {
+    let name = frame.name.as_deref().unwrap_or("<anonymous>");
+    let mut args = frame.args.iter()
+        .map(|x| pr_str(x, true))
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    // only a summary, big arguments would drown out the rest of the trace
+    if args.chars().count() > FRAME_ARGS {
+        args = format!("{}...", args.chars().take(FRAME_ARGS).collect::<String>());
+    }
+
+    let mut ret = if args.is_empty() {
+        format!("({})", name)
+    } else {
+        format!("({} {})", name, args)
+    };
+
+    if let Some(span) = &frame.span {
+        ret = format!("{} at {}", ret, span);
+    }
+    if frame.tail_calls > 0 {
+        let plural = if frame.tail_calls == 1 { "" } else { "s" };
+        ret = format!("{} [{} tail call{} elided]", ret, frame.tail_calls, plural);
+    }
+
+    ret
+}
+
+// error message prefixed with file:line:col and followed by the offending source line when the position is known,
+// then the stack trace
+pub fn pr_error(err: &LangError) -> String {
+    let mut ret = match &err.span {
+        Some(span) => {
+            let line = span.source.line(span.line);
+            // keep tabs so the caret lines up with the excerpt
+            let pad = line.chars()
+                .take(span.col - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect::<String>();
+
+            format!("{}: {}\n    {}\n    {}^", span, err, line, pad)
+        }
+        None => err.to_string()
+    };
+
+    for frame in &err.trace {
+        ret = format!("{}\n  in {}", ret, pr_frame(frame));
+    }
+
+    ret
+}
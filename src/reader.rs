@@ -1,38 +1,132 @@
 use regex::Regex;
-use crate::types::{Result, LangVal};
+use crate::types::{Result, LangVal, LangError, ErrorKind, Hashmap, MapKey, Meta, Source, Span};
+use std::io::BufRead;
 use std::rc::Rc;
-use std::collections::HashMap;
 use itertools::Itertools;
 use regex::Captures;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
+use crate::numeric::Num;
 
 #[derive(Debug, Clone)]
+pub struct Token {
+    pub text: String,
+    pub offset: usize // byte offset into the source text
+}
+
 struct Reader {
-    tokens: Vec<String>,
-    pos: usize
+    tokens: Vec<Token>,
+    pos: usize,
+    source: Rc<Source>
 }
 
 impl Reader {
     fn next(&mut self) -> Result<String> {
+        let token = self.peek()?;
         self.pos += 1;
-        Ok(self.tokens
-            .get(self.pos-1)
-            .ok_or("Not enough tokens (unbalanced brackets)")?
-            .to_string())
+        Ok(token)
     }
 
     fn peek(&self) -> Result<String> {
         Ok(self.tokens
             .get(self.pos)
-            .ok_or("Not enough tokens (unbalanced brackets)")?
-            .to_string())
+            .ok_or_else(|| LangError::incomplete("Not enough tokens (unbalanced brackets)", self.span()))?
+            .text
+            .clone())
+    }
+
+    // position of the next token, or of the end of input if there is none
+    fn span(&self) -> Span {
+        let offset = self.tokens
+            .get(self.pos)
+            .map_or(self.source.text.trim_end().len(), |t| t.offset);
+
+        Span::new(&self.source, offset)
+    }
+
+    fn error(&self, msg: &str) -> LangError {
+        LangError::reader(msg, self.span())
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
     }
 }
 
-pub fn tokenize(str: &str) -> Result<Vec<String>> {
-    if str.starts_with(";;") { // ignore lines that start with ;; for comments
-        return Ok(vec![]);
+// iterator over the top level forms of a stream, reading only as many lines as the next form
+// needs and stopping after the first error
+pub struct Forms<R> {
+    input: R,
+    name: String,
+    // lines the next form is on, starting with the line it begins on so columns stay right
+    reader: Reader,
+    done: bool
+}
+
+impl<R: BufRead> Forms<R> {
+    fn read_next(&mut self) -> Result<Option<LangVal>> {
+        let mut eof = false;
+
+        loop {
+            if !self.reader.at_end() {
+                let pos = self.reader.pos;
+
+                match read_form(&mut self.reader) {
+                    // the rest of the form may be on the next lines
+                    Err(err) if !eof && matches!(*err.kind, ErrorKind::Incomplete(_)) => self.reader.pos = pos,
+                    res => return res.map(Some)
+                }
+            } else if eof {
+                return Ok(None);
+            }
+
+            let mut line = String::new();
+            let read = self.input.read_line(&mut line)
+                .map_err(|why| format!("Could not read {}: {}", self.name, why))?;
+
+            if read == 0 {
+                eof = true;
+            } else {
+                self.push_line(&line)?;
+            }
+        }
     }
 
+    // adds a line to the text of the form being read, dropping the lines of forms before it
+    fn push_line(&mut self, line: &str) -> Result<()> {
+        let source = &self.reader.source;
+        let start = self.reader.tokens.get(self.reader.pos).map_or(source.text.len(), |t| t.offset);
+        let line_start = source.text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let first_line = source.line_col(line_start).0;
+
+        let text = format!("{}{}", &source.text[line_start..], line);
+        let tokens = tokenize(&text)?;
+
+        self.reader.pos = tokens.iter().position(|t| t.offset >= start - line_start).unwrap_or(tokens.len());
+        self.reader.tokens = tokens;
+        self.reader.source = Source::starting_at(&self.name, &text, first_line);
+
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for Forms<R> {
+    type Item = Result<LangVal>;
+
+    fn next(&mut self) -> Option<Result<LangVal>> {
+        if self.done {
+            return None;
+        }
+
+        let res = self.read_next().transpose();
+        self.done = !matches!(res, Some(Ok(_)));
+
+        res
+    }
+}
+
+pub fn tokenize(str: &str) -> Result<Vec<Token>> {
     lazy_static! {
         static ref PARSE_RE: Regex = Regex::new(
             r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
@@ -43,10 +137,15 @@ pub fn tokenize(str: &str) -> Result<Vec<String>> {
     let mut res = vec![];
 
     for cap in PARSE_RE.captures_iter(str) {
-        if cap[1].starts_with(";") { // in line comment
+        let tok = cap.get(1).unwrap();
+
+        if tok.as_str().starts_with(";") { // in line comment
             continue;
         }
-        res.push(String::from(&cap[1]));
+        res.push(Token {
+            text: String::from(tok.as_str()),
+            offset: tok.start()
+        });
     }
 
     Ok(res)
@@ -55,19 +154,36 @@ fn unescape_str(s: &str) -> String {
     lazy_static! {
         static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
     }
-    RE.replace_all(&s, |caps: &Captures| {
-        format!("{}", if &caps[1] == "n" { "\n" } else { &caps[1] })
+    RE.replace_all(s, |caps: &Captures| {
+        (if &caps[1] == "n" { "\n" } else { &caps[1] }).to_string()
     })
         .to_string()
 }
 
+// integers too big for an i64 become big integers
+fn read_integer(digits: &str, radix: u32, token: &str, span: Span) -> Result<LangVal> {
+    match BigInt::parse_bytes(digits.trim_start_matches('+').as_bytes(), radix) {
+        Some(n) => Ok(Num::Big(n).into_val()),
+        None => Err(LangError::reader(&format!("Invalid number {}", token), span))
+    }
+}
+
 fn read_atomic(reader: &mut Reader) -> Result<LangVal> {
 
     lazy_static! {
-        static ref NUM_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
+        // digits may be grouped with single underscores, like 1_000_000
+        static ref NUM_RE: Regex = Regex::new(
+            r"^[+-]?[0-9](?:_?[0-9])*(?:\.[0-9](?:_?[0-9])*)?(?:[eE][+-]?[0-9](?:_?[0-9])*)?$"
+        ).unwrap();
+        static ref RADIX_RE: Regex = Regex::new(
+            r"^([+-]?)0([xXbB])([0-9a-fA-F](?:_?[0-9a-fA-F])*)$"
+        ).unwrap();
+        static ref RATIO_RE: Regex = Regex::new(r"^([+-]?[0-9](?:_?[0-9])*)/([0-9](?:_?[0-9])*)$").unwrap();
+        static ref NUM_START_RE: Regex = Regex::new(r"^[+-]?[0-9]").unwrap();
         static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
     }
 
+    let span = reader.span();
     let token = reader.next()?;
 
     // cases
@@ -80,49 +196,79 @@ fn read_atomic(reader: &mut Reader) -> Result<LangVal> {
     if token == "false" {
         return Ok(LangVal::Boolean(false));
     }
-    if token.starts_with(":") {
-        return Ok(LangVal::String(format!("\u{29e}{}", &token[1..]).to_string()));
+    if let Some(name) = token.strip_prefix(":") {
+        return Ok(LangVal::keyword(name));
     }
     if NUM_RE.is_match(&token) {
-        return Ok(LangVal::Number(token.parse()?));
+        let digits = token.trim_start_matches('+').replace('_', "");
+
+        // only a fraction or exponent makes it a float
+        if digits.contains(['.', 'e', 'E']) {
+            return Ok(LangVal::Float(digits.parse().map_err(|_| {
+                LangError::reader(&format!("Invalid number {}", token), span.clone())
+            })?));
+        }
+
+        return read_integer(&digits, 10, &token, span);
+    }
+    if let Some(caps) = RATIO_RE.captures(&token) {
+        let numer = BigInt::parse_bytes(caps[1].trim_start_matches('+').replace('_', "").as_bytes(), 10);
+        let denom = BigInt::parse_bytes(caps[2].replace('_', "").as_bytes(), 10);
+
+        return match (numer, denom) {
+            (Some(numer), Some(denom)) if !denom.is_zero() => {
+                Ok(Num::Ratio(BigRational::new(numer, denom)).into_val())
+            }
+            _ => Err(LangError::reader(&format!("Invalid number {}", token), span))
+        };
+    }
+    if let Some(caps) = RADIX_RE.captures(&token) {
+        let radix = if caps[2].eq_ignore_ascii_case("x") { 16 } else { 2 };
+
+        return read_integer(&format!("{}{}", &caps[1], caps[3].replace('_', "")), radix, &token, span);
+    }
+    match token.as_str() {
+        "##Inf" => return Ok(LangVal::Float(f64::INFINITY)),
+        "##-Inf" => return Ok(LangVal::Float(f64::NEG_INFINITY)),
+        "##NaN" => return Ok(LangVal::Float(f64::NAN)),
+        _ => {}
+    }
+    if NUM_START_RE.is_match(&token) { // symbols can't start with a digit, so this is a typo'd number
+        Err(LangError::reader(&format!("Invalid number {}", token), span.clone()))?;
     }
     if STR_RE.is_match(&token) {
         return Ok(LangVal::String(unescape_str(&token[1..(token.len()-1)])));
     }
     if token.starts_with("\"") {
-        Err("Unexpected \" (unbalanced string literal)")?;
+        Err(LangError::incomplete("Unexpected \" (unbalanced string literal)", span))?;
     }
 
     Ok(LangVal::Symbol(token))
 }
 
-fn make_hashmap(tokens: Vec<LangVal>) -> Result<LangVal> {
+fn make_hashmap(tokens: Vec<LangVal>, span: Span) -> Result<LangVal> {
 
-    if tokens.len() % 2 != 0 {
-        Err("Invalid size hashmap")?;
+    if !tokens.len().is_multiple_of(2) {
+        Err(LangError::reader("Invalid size hashmap", span.clone()))?;
     }
 
-    let mut mp: HashMap<String, LangVal> = Default::default();
+    let mut mp = Hashmap::default();
 
     for (k, v) in tokens.into_iter().tuples() {
-        match k {
-            LangVal::Number(n) => {
-                mp.insert(n.to_string(), v.clone());
-            }
-            LangVal::String(s) => {
-                mp.insert(s, v.clone());
-            }
-            _ => {
-                Err("Invalid hashmap key type")?
+        match MapKey::from_val(&k) {
+            Some(key) => {
+                mp.insert(key, v.clone());
             }
+            None => Err(LangError::reader("Invalid hashmap key type", span.clone()))?
         }
     }
 
-    Ok(LangVal::Hashmap(mp))
+    Ok(LangVal::Hashmap(mp, Meta { span: Some(span), value: None }))
 }
 
 fn read_list(reader: &mut Reader, end: &str) -> Result<LangVal> {
     let mut ret: Vec<LangVal> = vec![];
+    let span = reader.span();
     reader.next()?;
 
     loop {
@@ -136,10 +282,10 @@ fn read_list(reader: &mut Reader, end: &str) -> Result<LangVal> {
     reader.next()?;
 
     match end {
-        ")" => Ok(LangVal::List(ret)),
-        "]" => Ok(LangVal::Vector(ret)),
-        "}" => make_hashmap(ret),
-        _ => Err("Unknown ending")?
+        ")" => Ok(LangVal::List(ret, Meta { span: Some(span), value: None })),
+        "]" => Ok(LangVal::Vector(ret, Meta { span: Some(span), value: None })),
+        "}" => make_hashmap(ret, span),
+        _ => Err(LangError::reader("Unknown ending", span))?
     }
 }
 
@@ -168,27 +314,158 @@ fn read_form(reader: &mut Reader) -> Result<LangVal>{
             Ok(LangVal::WithSpecial(("deref".to_string(), Rc::new(read_form(reader)?))))
         }
         "^" => {
+            let span = reader.span();
             reader.next()?;
             let meta = read_form(reader)?;
-            Ok(LangVal::List(vec![LangVal::Symbol("with-meta".to_string()), read_form(reader)?, meta]))
+            Ok(LangVal::List(vec![LangVal::Symbol("with-meta".to_string()), read_form(reader)?, meta],
+                             Meta { span: Some(span), value: None }))
         }
-        ")" => Err("Unexpected ')'")?,
+        ")" => Err(reader.error("Unexpected ')'"))?,
         "(" => read_list(reader, ")"),
-        "]" => Err("Unexpected ']'")?,
+        "]" => Err(reader.error("Unexpected ']'"))?,
         "[" => read_list(reader, "]"),
-        "}" => Err("Unexpected '}'")?,
+        "}" => Err(reader.error("Unexpected '}'"))?,
         "{" => read_list(reader, "}"),
         _ => read_atomic(reader)
     }
 }
 
 pub fn read_str(str: &str) -> Result<LangVal>{
-    let tokens = tokenize(str)?;
+    read_source(Source::new("<string>", str))
+}
+
+// reads the only form in named source text, recording where each collection started
+pub fn read_source(source: Rc<Source>) -> Result<LangVal>{
+    let mut reader = Reader {
+        tokens: tokenize(&source.text)?,
+        pos: 0,
+        source
+    };
+
+    let ret = read_form(&mut reader)?;
+
+    if !reader.at_end() {
+        Err(reader.error("Unexpected input after the end of the form"))?;
+    }
+
+    Ok(ret)
+}
+
+// reads forms from input as they are needed, like a file that is evaluated as it is read
+pub fn read_forms<R: BufRead>(name: &str, input: R) -> Forms<R> {
+    Forms {
+        input,
+        name: name.to_string(),
+        reader: Reader {
+            tokens: vec![],
+            pos: 0,
+            source: Source::new(name, "")
+        },
+        done: false
+    }
+}
+
+// reads every top level form, so nothing is returned if any of them is malformed
+pub fn read_all(source: Rc<Source>) -> Result<Vec<LangVal>> {
+    let mut reader = Reader {
+        tokens: tokenize(&source.text)?,
+        pos: 0,
+        source
+    };
+    let mut ret = vec![];
+
+    while !reader.at_end() {
+        ret.push(read_form(&mut reader)?);
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer::pr_str;
+
+    fn read(src: &str) -> String {
+        match read_str(src) {
+            Ok(val) => pr_str(&val, true),
+            Err(err) => err.to_string()
+        }
+    }
+
+    #[test]
+    fn integer_literals() {
+        assert_eq!(read("42"), "42");
+        assert_eq!(read("+7"), "7");
+        assert_eq!(read("-1_000_000"), "-1000000");
+        assert_eq!(read("0xFF"), "255");
+        assert_eq!(read("-0b1010"), "-10");
+        assert!(matches!(read_str("9223372036854775807"), Ok(LangVal::Integer(_))));
+        assert!(matches!(read_str("9223372036854775808"), Ok(LangVal::BigInteger(_))));
+    }
+
+    #[test]
+    fn float_literals() {
+        assert_eq!(read("1.5"), "1.5");
+        assert_eq!(read("-0.25"), "-0.25");
+        assert!(matches!(read_str("1e9"), Ok(LangVal::Float(f)) if f == 1e9));
+        assert!(matches!(read_str("1_0.5e-1"), Ok(LangVal::Float(f)) if f == 1.05));
+        assert!(matches!(read_str("##Inf"), Ok(LangVal::Float(f)) if f == f64::INFINITY));
+        assert!(matches!(read_str("##-Inf"), Ok(LangVal::Float(f)) if f == f64::NEG_INFINITY));
+        assert!(matches!(read_str("##NaN"), Ok(LangVal::Float(f)) if f.is_nan()));
+    }
+
+    #[test]
+    fn ratio_literals_are_normalized() {
+        assert_eq!(read("3/4"), "3/4");
+        assert_eq!(read("6/8"), "3/4");
+        assert_eq!(read("-4/2"), "-2");
+        assert!(matches!(read_str("4/2"), Ok(LangVal::Integer(2))));
+    }
+
+    #[test]
+    fn malformed_numbers_are_errors() {
+        for src in ["1x", "1/0", "1__0", "0xG", "1.", "1/-2"] {
+            assert!(read(src).contains(&format!("Invalid number {}", src)), "{} read as {}", src, read(src));
+        }
+    }
 
-    //println!("{:?}", tokens);
+    #[test]
+    fn forms_are_read_from_a_stream_as_needed() {
+        let mut input = std::io::Cursor::new("(def! a 1) [2\n  3]\n; comment\n\n  (+ a\n 4)\n");
+        let mut forms = read_forms("<stream>", &mut input);
 
-    Ok(read_form(&mut Reader {
-        tokens,
-        pos: 0
-    })?)
+        assert_eq!(forms.next().map(|f| pr_str(&f.unwrap(), true)), Some("(def! a 1)".to_string()));
+        assert_eq!(forms.next().map(|f| pr_str(&f.unwrap(), true)), Some("[2 3]".to_string()));
+
+        // lines are only read once a form needs them
+        assert_eq!(forms.input.position() as usize, "(def! a 1) [2\n  3]\n".len());
+
+        let last = forms.next().unwrap().unwrap();
+        assert_eq!(pr_str(&last, true), "(+ a 4)");
+        assert_eq!(last.meta().and_then(|m| m.span.clone()).map(|s| s.to_string()), Some("<stream>:5:3".to_string()));
+        assert!(forms.next().is_none());
+    }
+
+    #[test]
+    fn stream_errors_end_the_forms() {
+        let forms: Vec<_> = read_forms("<stream>", "1 (2\n".as_bytes()).map(|f| f.map_err(|err| err.to_string())).collect();
+        assert_eq!(forms, vec![Ok(LangVal::Integer(1)), Err("Not enough tokens (unbalanced brackets)".to_string())]);
+
+        let forms: Vec<_> = read_forms("<stream>", ")\n1\n".as_bytes()).collect();
+        assert_eq!(forms.len(), 1);
+        assert!(forms[0].is_err());
+    }
+
+    #[test]
+    fn unfinished_input_is_incomplete_rather_than_malformed() {
+        let kind = |src: &str| read_all(Source::new("<test>", src)).err().map(|err| *err.kind);
+
+        for src in ["(+ 1", "[1 (2 3)", "{:a", "\"abc", "'", "(def! s \"a\\\""] {
+            assert!(matches!(kind(src), Some(ErrorKind::Incomplete(_))), "{}", src);
+        }
+        for src in ["(+ 1))", "]", "{:a}", "(1x)"] {
+            assert!(matches!(kind(src), Some(ErrorKind::Reader(_))), "{}", src);
+        }
+    }
 }
@@ -1,9 +1,16 @@
 use std::error::Error;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::fmt;
+use std::hash::{Hash, Hasher, DefaultHasher};
+use crate::printer::pr_str;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use crate::numeric::{self, Num};
+use std::cmp::Ordering;
 
-pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
-pub type Hashmap = std::collections::HashMap<String, LangVal>;
+pub type Result<T> = std::result::Result<T, LangError>;
+pub type Hashmap = std::collections::HashMap<MapKey, LangVal>;
 pub type LangFunction = fn(Vec<LangVal>, Env) -> Result<LangVal>;
 pub type TCOFunction = fn(Vec<LangVal>, Env) -> Result<(LangVal, Env)>;
 
@@ -13,33 +20,284 @@ pub enum LangVal {
     // definitely gonna be used
     Nil,
     Boolean(bool),
-    List(Vec<LangVal>),
-    Vector(Vec<LangVal>),
-    Number(f64),
+    List(Vec<LangVal>, Meta),
+    Vector(Vec<LangVal>, Meta),
+    Integer(i64),
+    BigInteger(BigInt), // integers that overflowed an i64
+    Ratio(BigRational), // always in lowest terms and never a whole number
+    Float(f64),
     String(String),
+    Keyword(Rc<str>), // interned, so every :a shares the same name
     Symbol(String),
-    Hashmap(Hashmap),
+    Hashmap(Hashmap, Meta),
     Function(LangFunction),
     SpecialFunction(LangFunction), // functions where arguments are given in raw and unevaluated
     TCOFunction(TCOFunction), // TCO optimized function that needs to be directly implemented in the loop
     DefinedFunction {
-        symbols: Vec<String>,
-        ast: Box<LangVal>,
+        clauses: Rc<Vec<Clause>>, // one per arity, at most one of them can take a given number of arguments
         env: Env,
-        min_args: usize,
-        is_variadic: bool
+        is_macro: bool,
+        name: Option<String>, // symbol it was first bound to with def!
+        meta: Meta
     },
+    Atom(Rc<Atom>),
     // quotes, etc
     WithSpecial((String, Rc<LangVal>))
 }
 
+// hashmap key, equal to another key when the values are = except that NaN is equal to itself,
+// so it can still be looked up
+#[derive(Clone)]
+pub struct MapKey(pub LangVal);
+
+impl MapKey {
+    // None for values that can't be hashed, like functions or collections holding them
+    pub fn from_val(val: &LangVal) -> Option<MapKey> {
+        if hashable(val) { Some(MapKey(val.clone())) } else { None }
+    }
+
+    pub fn to_val(&self) -> LangVal {
+        self.0.clone()
+    }
+}
+
+fn hashable(val: &LangVal) -> bool {
+    match val {
+        LangVal::List(v, _)|LangVal::Vector(v, _) => v.iter().all(hashable),
+        LangVal::Hashmap(mp, _) => mp.values().all(hashable),
+        // a quoted key like 'a in a hashmap literal, which is only evaluated later
+        LangVal::WithSpecial((_, inner)) => hashable(inner),
+        LangVal::Function(_)|
+        LangVal::SpecialFunction(_)|
+        LangVal::TCOFunction(_)|
+        LangVal::DefinedFunction {..} => false,
+        _ => true
+    }
+}
+
+// finite floats are turned into the exact number they hold, so 0.5 and 1/2 or 1.0 and 1
+// are the same key, and -0.0 the same as 0
+fn exact(val: &LangVal) -> Option<LangVal> {
+    match Num::from_val(val)? {
+        Num::Float(f) if f.is_finite() => Some(Num::Ratio(BigRational::from_float(f).unwrap()).into_val()),
+        n => Some(n.into_val())
+    }
+}
+
+fn key_hash<H: Hasher>(val: &LangVal, state: &mut H) {
+    if let Some(n) = exact(val) {
+        match n {
+            LangVal::Integer(n) => n.hash(state),
+            LangVal::BigInteger(n) => n.hash(state),
+            LangVal::Ratio(n) => n.hash(state),
+            // only infinities and NaN are left as floats
+            LangVal::Float(n) => if n.is_nan() { f64::NAN.to_bits().hash(state) } else { n.to_bits().hash(state) },
+            _ => {}
+        }
+        return;
+    }
+
+    // a tag per kind of value, so a string and a symbol with the same name don't always collide
+    match val {
+        LangVal::Boolean(b) => (1u8, b).hash(state),
+        LangVal::String(s) => (2u8, s).hash(state),
+        LangVal::Symbol(s) => (3u8, s).hash(state),
+        LangVal::Keyword(k) => (4u8, k).hash(state),
+        LangVal::Atom(a) => (5u8, Rc::as_ptr(a)).hash(state),
+        // lists and vectors with the same elements are =, so they have to hash the same
+        LangVal::List(v, _)|LangVal::Vector(v, _) => {
+            (6u8, v.len()).hash(state);
+            for x in v {
+                key_hash(x, state);
+            }
+        }
+        // entries are hashed on their own and added up, since their order is arbitrary
+        LangVal::Hashmap(mp, _) => {
+            let sum = mp.iter().fold(0u64, |sum, (k, v)| {
+                let mut entry = DefaultHasher::new();
+                k.hash(&mut entry);
+                key_hash(v, &mut entry);
+                sum.wrapping_add(entry.finish())
+            });
+            (7u8, sum).hash(state);
+        }
+        LangVal::WithSpecial((name, inner)) => {
+            (8u8, name).hash(state);
+            key_hash(inner, state);
+        }
+        _ => 0u8.hash(state)
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &MapKey) -> bool {
+        // the same relation as =, except a NaN key can still be found again
+        equal(&self.0, &other.0, true)
+    }
+}
+
+impl Eq for MapKey {}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        key_hash(&self.0, state)
+    }
+}
+
+// parameters and body of one arity of a defined function
+#[derive(Clone)]
+pub struct Clause {
+    pub params: Vec<LangVal>, // symbols or destructuring patterns
+    pub optional: Vec<(LangVal, LangVal)>, // parameters after &optional, with the forms giving their defaults
+    pub rest: Option<LangVal>, // parameter after &
+    pub keys: Vec<(String, LangVal)>, // names after &key, with the forms giving their defaults
+    pub ast: LangVal
+}
+
+impl Clause {
+    // fewest and most arguments it takes, no most with a rest parameter or keyword arguments
+    pub fn arity(&self) -> (usize, Option<usize>) {
+        let min = self.params.len();
+
+        if self.rest.is_some() || !self.keys.is_empty() {
+            (min, None)
+        } else {
+            (min, Some(min + self.optional.len()))
+        }
+    }
+
+    pub fn accepts(&self, n: usize) -> bool {
+        let (min, max) = self.arity();
+
+        n >= min && max.is_none_or(|max| n <= max)
+    }
+}
+
+// mutable cell shared by every copy of an atom value
+pub struct Atom {
+    pub value: RefCell<LangVal>,
+    // (key, function) pairs, each function is called with key, atom, old and new value after every change
+    pub watches: RefCell<Vec<(LangVal, LangVal)>>
+}
+
+// text that forms were read from, kept around so errors can point into it
+pub struct Source {
+    pub name: String,
+    pub text: String,
+    line_starts: Vec<usize>,
+    // line number of the first line of text, which is past 1 for a piece of a stream
+    first_line: usize
+}
+
+impl Source {
+    pub fn new(name: &str, text: &str) -> Rc<Source> {
+        Source::starting_at(name, text, 1)
+    }
+
+    pub fn starting_at(name: &str, text: &str, first_line: usize) -> Rc<Source> {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+
+        Rc::new(Source {
+            name: name.to_string(),
+            text: text.to_string(),
+            line_starts,
+            first_line
+        })
+    }
+
+    // 1-based line and column of a byte offset into the text
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(i) => i,
+            Err(i) => i - 1
+        };
+        let col = self.text[self.line_starts[line]..offset].chars().count();
+
+        (line + self.first_line, col + 1)
+    }
+
+    pub fn line(&self, line: usize) -> &str {
+        self.text.lines().nth(line - self.first_line).unwrap_or("")
+    }
+}
+
+// position of a read form in its source
+#[derive(Clone)]
+pub struct Span {
+    pub source: Rc<Source>,
+    pub line: usize,
+    pub col: usize
+}
+
+impl Span {
+    pub fn new(source: &Rc<Source>, offset: usize) -> Span {
+        let (line, col) = source.line_col(offset);
+
+        Span {
+            source: source.clone(),
+            line,
+            col
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source.name, self.line, self.col)
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+// extra information carried alongside a collection or function that isn't part of its value
+#[derive(Clone, Default)]
+pub struct Meta {
+    pub span: Option<Span>,
+    // user metadata set with with-meta, None is the same as nil
+    pub value: Option<Rc<LangVal>>
+}
+
 #[allow(dead_code)]
 impl LangVal {
+    pub fn list(v: Vec<LangVal>) -> LangVal {
+        LangVal::List(v, Meta::default())
+    }
+    pub fn vector(v: Vec<LangVal>) -> LangVal {
+        LangVal::Vector(v, Meta::default())
+    }
+    pub fn hashmap(mp: Hashmap) -> LangVal {
+        LangVal::Hashmap(mp, Meta::default())
+    }
+    // keyword with the given name, without the leading :
+    pub fn keyword(name: &str) -> LangVal {
+        thread_local! {
+            static KEYWORDS: RefCell<std::collections::HashSet<Rc<str>>> = RefCell::new(Default::default());
+        }
+
+        KEYWORDS.with(|keywords| {
+            let mut keywords = keywords.borrow_mut();
+
+            match keywords.get(name) {
+                Some(k) => LangVal::Keyword(k.clone()),
+                None => {
+                    let k: Rc<str> = Rc::from(name);
+                    keywords.insert(k.clone());
+                    LangVal::Keyword(k)
+                }
+            }
+        })
+    }
     pub fn try_function(self) -> Option<LangFunction> {
         if let LangVal::Function(v) = self { Some(v) } else { None }
     }
     pub fn try_list(self) -> Option<Vec<LangVal>> {
-        if let LangVal::List(v) = self { Some(v) } else { None }
+        if let LangVal::List(v, _) = self { Some(v) } else { None }
     }
     pub fn try_symbol(self) -> Option<String> {
         if let LangVal::Symbol(v) = self { Some(v) } else { None }
@@ -47,6 +305,291 @@ impl LangVal {
     pub fn try_boolean(self) -> Option<bool> {
         if let LangVal::Boolean(v) = self { Some(v) } else { None }
     }
+
+    // metadata slot of values that can carry metadata
+    pub fn meta(&self) -> Option<&Meta> {
+        match self {
+            LangVal::List(_, meta)|
+            LangVal::Vector(_, meta)|
+            LangVal::Hashmap(_, meta)|
+            LangVal::DefinedFunction {meta, ..} => Some(meta),
+            _ => None
+        }
+    }
+    pub fn meta_mut(&mut self) -> Option<&mut Meta> {
+        match self {
+            LangVal::List(_, meta)|
+            LangVal::Vector(_, meta)|
+            LangVal::Hashmap(_, meta)|
+            LangVal::DefinedFunction {meta, ..} => Some(meta),
+            _ => None
+        }
+    }
+
+    // name of the value's type as shown in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LangVal::Nil => "nil",
+            LangVal::Boolean(_) => "boolean",
+            LangVal::List(_, _) => "list",
+            LangVal::Vector(_, _) => "vector",
+            LangVal::Integer(_)|LangVal::BigInteger(_) => "integer",
+            LangVal::Ratio(_) => "ratio",
+            LangVal::Float(_) => "float",
+            LangVal::String(_) => "string",
+            LangVal::Keyword(_) => "keyword",
+            LangVal::Symbol(_) => "symbol",
+            LangVal::Hashmap(_, _) => "hashmap",
+            LangVal::DefinedFunction {is_macro: true, ..} => "macro",
+            LangVal::Function(_)|
+            LangVal::SpecialFunction(_)|
+            LangVal::TCOFunction(_)|
+            LangVal::DefinedFunction {..} => "function",
+            LangVal::Atom(_) => "atom",
+            LangVal::WithSpecial(_) => "form"
+        }
+    }
+}
+
+impl fmt::Debug for LangVal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", pr_str(self, true))
+    }
+}
+
+// structural equality, what = uses. numbers are equal by value whatever their type, lists equal
+// vectors with the same elements, metadata is ignored and functions are equal to their copies.
+// map keys use the same relation with nan_equal set, so a NaN key can be looked up again
+fn equal(a: &LangVal, b: &LangVal, nan_equal: bool) -> bool {
+    if let (Some(x), Some(y)) = (Num::from_val(a), Num::from_val(b)) {
+        return match numeric::cmp(&x, &y) {
+            Some(ord) => ord == Ordering::Equal,
+            None => nan_equal && matches!((x, y), (Num::Float(x), Num::Float(y)) if x.is_nan() && y.is_nan())
+        };
+    }
+
+    match (a, b) {
+        (LangVal::Nil, LangVal::Nil) => true,
+        (LangVal::Boolean(a), LangVal::Boolean(b)) => a == b,
+        (LangVal::String(a), LangVal::String(b))|
+        (LangVal::Symbol(a), LangVal::Symbol(b)) => a == b,
+        (LangVal::Keyword(a), LangVal::Keyword(b)) => a == b,
+        (LangVal::List(v1, _)|LangVal::Vector(v1, _), LangVal::List(v2, _)|LangVal::Vector(v2, _)) => {
+            v1.len() == v2.len() && v1.iter().zip(v2).all(|(a, b)| equal(a, b, nan_equal))
+        }
+        (LangVal::Hashmap(m1, _), LangVal::Hashmap(m2, _)) => {
+            m1.len() == m2.len() && m1.iter().all(|(k, v1)| m2.get(k).is_some_and(|v2| equal(v1, v2, nan_equal)))
+        }
+        (LangVal::Function(f), LangVal::Function(g))|
+        (LangVal::SpecialFunction(f), LangVal::SpecialFunction(g)) => std::ptr::fn_addr_eq(*f, *g),
+        (LangVal::TCOFunction(f), LangVal::TCOFunction(g)) => std::ptr::fn_addr_eq(*f, *g),
+        (LangVal::DefinedFunction {clauses: c1, env: e1, is_macro: m1, ..},
+         LangVal::DefinedFunction {clauses: c2, env: e2, is_macro: m2, ..}) => {
+            // a function is only equal to itself, two lambdas with the same code are still different values
+            Rc::ptr_eq(c1, c2) && Rc::ptr_eq(e1, e2) && m1 == m2
+        }
+        (LangVal::Atom(a), LangVal::Atom(b)) => Rc::ptr_eq(a, b),
+        (LangVal::WithSpecial((n1, a)), LangVal::WithSpecial((n2, b))) => n1 == n2 && equal(a, b, nan_equal),
+        _ => false
+    }
+}
+
+impl PartialEq for LangVal {
+    fn eq(&self, other: &LangVal) -> bool {
+        equal(self, other, false)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ErrorKind {
+    // function called with the wrong number of arguments, arities are the (min, max) counts it
+    // does take, max being None when variadic
+    Arity {
+        name: String,
+        arities: Vec<(usize, Option<usize>)>,
+        got: usize
+    },
+    // argument of the wrong type
+    Type {
+        name: String,
+        expected: String,
+        got: LangVal
+    },
+    UnboundSymbol(String),
+    // malformed source text
+    Reader(String),
+    // source text that stops in the middle of a form, so more input could still make it valid
+    Incomplete(String),
+    // value raised by throw
+    Thrown(LangVal),
+    // anything else, like division by 0, a malformed special form or binding pattern
+    Other(String)
+}
+
+// call to a user defined function that was in progress when an error happened
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub name: Option<String>,
+    pub args: Vec<LangVal>,
+    // form the call was made from
+    pub span: Option<Span>,
+    // how many tail calls replaced this frame before getting to the current function
+    pub tail_calls: usize
+}
+
+#[derive(Debug, Clone)]
+pub struct LangError {
+    pub kind: Box<ErrorKind>,
+    // innermost read form being evaluated when the error happened
+    pub span: Option<Span>,
+    // innermost call first
+    pub trace: Vec<Frame>
+}
+
+impl LangError {
+    pub fn type_error(name: &str, expected: &str, got: &LangVal) -> LangError {
+        ErrorKind::Type {
+            name: name.to_string(),
+            expected: expected.to_string(),
+            got: got.clone()
+        }.into()
+    }
+
+    pub fn reader(msg: &str, span: Span) -> LangError {
+        LangError {
+            kind: Box::new(ErrorKind::Reader(msg.to_string())),
+            span: Some(span),
+            trace: vec![]
+        }
+    }
+
+    pub fn incomplete(msg: &str, span: Span) -> LangError {
+        LangError {
+            kind: Box::new(ErrorKind::Incomplete(msg.to_string())),
+            span: Some(span),
+            trace: vec![]
+        }
+    }
+
+    // attaches span unless the error already points somewhere more specific
+    pub fn at(mut self, span: &Option<Span>) -> LangError {
+        if self.span.is_none() {
+            self.span = span.clone();
+        }
+        self
+    }
+}
+
+// errors out unless function name got between min and max (if any) arguments
+pub fn check_arity(name: &str, args: &[LangVal], min: usize, max: Option<usize>) -> Result<()> {
+    if args.len() < min || max.is_some_and(|max| args.len() > max) {
+        Err(ErrorKind::Arity {
+            name: name.to_string(),
+            arities: vec![(min, max)],
+            got: args.len()
+        })?;
+    }
+
+    Ok(())
+}
+
+// elements of anything that can be walked through in order, characters for strings and
+// [key value] entries for hashmaps
+pub fn seq(name: &str, val: &LangVal) -> Result<Vec<LangVal>> {
+    match val {
+        LangVal::List(v, _)|LangVal::Vector(v, _) => Ok(v.clone()),
+        LangVal::Nil => Ok(vec![]),
+        LangVal::String(s) => Ok(s.chars().map(|c| LangVal::String(c.to_string())).collect()),
+        LangVal::Hashmap(mp, _) => {
+            Ok(mp.iter().map(|(k, v)| LangVal::vector(vec![k.to_val(), v.clone()])).collect())
+        }
+        _ => Err(LangError::type_error(name, "a list, vector, hashmap, string or nil", val))
+    }
+}
+
+// number of elements seq would give, without copying them
+pub fn seq_len(name: &str, val: &LangVal) -> Result<usize> {
+    match val {
+        LangVal::List(v, _)|LangVal::Vector(v, _) => Ok(v.len()),
+        LangVal::Nil => Ok(0),
+        LangVal::String(s) => Ok(s.chars().count()),
+        LangVal::Hashmap(mp, _) => Ok(mp.len()),
+        _ => Err(LangError::type_error(name, "a list, vector, hashmap, string or nil", val))
+    }
+}
+
+// element i of what seq would give, only copying that one
+pub fn seq_nth(name: &str, val: &LangVal, i: usize) -> Result<Option<LangVal>> {
+    match val {
+        LangVal::List(v, _)|LangVal::Vector(v, _) => Ok(v.get(i).cloned()),
+        LangVal::Nil => Ok(None),
+        LangVal::String(s) => Ok(s.chars().nth(i).map(|c| LangVal::String(c.to_string()))),
+        LangVal::Hashmap(mp, _) => Ok(mp.iter().nth(i).map(|(k, v)| LangVal::vector(vec![k.to_val(), v.clone()]))),
+        _ => Err(LangError::type_error(name, "a list, vector, hashmap, string or nil", val))
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Arity {name, arities, got} => {
+                let counts: Vec<String> = arities.iter().map(|(min, max)| match max {
+                    Some(max) if max == min => format!("{}", min),
+                    Some(max) => format!("{} to {}", min, max),
+                    None => format!("at least {}", min)
+                }).collect();
+                // like "1, 2 or at least 4"
+                let expected = match counts.split_last() {
+                    Some((last, [])) => last.clone(),
+                    Some((last, init)) => format!("{} or {}", init.join(", "), last),
+                    None => "no".to_string()
+                };
+                let plural = match arities.last() {
+                    Some((min, max)) if max.unwrap_or(*min) == 1 => "",
+                    _ => "s"
+                };
+
+                write!(f, "{} expected {} argument{}, got {}", name, expected, plural, got)
+            }
+            ErrorKind::Type {name, expected, got} => {
+                write!(f, "{} expected {}, got {}: {}", name, expected, got.type_name(), pr_str(got, true))
+            }
+            ErrorKind::UnboundSymbol(s) => write!(f, "Symbol {} not found", s),
+            ErrorKind::Reader(msg)|ErrorKind::Incomplete(msg) => write!(f, "{}", msg),
+            ErrorKind::Thrown(val) => write!(f, "Uncaught exception: {}", pr_str(val, true)),
+            ErrorKind::Other(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+impl fmt::Display for LangError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl Error for LangError {}
+
+impl From<ErrorKind> for LangError {
+    fn from(kind: ErrorKind) -> LangError {
+        LangError {
+            kind: Box::new(kind),
+            span: None,
+            trace: vec![]
+        }
+    }
+}
+
+impl From<&str> for LangError {
+    fn from(msg: &str) -> LangError {
+        ErrorKind::Other(msg.to_string()).into()
+    }
+}
+
+impl From<String> for LangError {
+    fn from(msg: String) -> LangError {
+        ErrorKind::Other(msg).into()
+    }
 }
 
 // environment implementation
@@ -80,7 +623,15 @@ pub fn env_get(env: &Env, key: &String) -> Result<LangVal> {
                 .get(key)
                 .unwrap()
                 .clone()),
-        None => Err(format!("Symbol {} not found", key))?
+        None => Err(ErrorKind::UnboundSymbol(key.clone()))?
+    }
+}
+
+// outermost environment, the one with the core functions in it
+pub fn env_root(env: &Env) -> Env {
+    match &env.outer {
+        Some(outer) => env_root(outer),
+        None => env.clone()
     }
 }
 
@@ -88,10 +639,75 @@ pub fn env_set(env: &Env, key: &str, val: LangVal) {
     env.data.borrow_mut().insert(key.to_string(), val);
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::BigInt;
+
+    fn ratio(numer: i64, denom: i64) -> LangVal {
+        Num::Ratio(BigRational::new(BigInt::from(numer), BigInt::from(denom))).into_val()
+    }
 
+    #[test]
+    fn numbers_equal_by_value_across_types() {
+        assert_eq!(LangVal::Integer(1), LangVal::Float(1.0));
+        assert_eq!(ratio(1, 2), LangVal::Float(0.5));
+        assert_eq!(LangVal::BigInteger(BigInt::from(1) << 70), LangVal::Float(2f64.powi(70)));
+        assert_ne!(LangVal::Integer(9007199254740993), LangVal::Float(9007199254740992.0));
+        assert_ne!(LangVal::Float(f64::NAN), LangVal::Float(f64::NAN));
+    }
 
+    #[test]
+    fn float_equality_is_transitive() {
+        // 0.1 is really 3602879701896397/36028797018963968, which isn't 1/10
+        let tenth = ratio(1, 10);
+        let float = LangVal::Float(0.1);
+        let exact = ratio(3602879701896397, 36028797018963968);
 
+        assert_eq!(float, exact);
+        assert_ne!(tenth, float);
+        assert_ne!(tenth, exact);
+    }
 
+    fn key_hash_of(val: &LangVal) -> u64 {
+        let mut state = DefaultHasher::new();
+        MapKey(val.clone()).hash(&mut state);
+        state.finish()
+    }
 
+    // keys that are the same have to hash the same, or lookups miss them
+    #[test]
+    fn map_keys_hash_the_same_when_equal() {
+        let pairs = [
+            (LangVal::Integer(1), LangVal::Float(1.0)),
+            (ratio(1, 2), LangVal::Float(0.5)),
+            (LangVal::Integer(0), LangVal::Float(-0.0)),
+            (LangVal::Float(f64::NAN), LangVal::Float(-f64::NAN)),
+            (LangVal::List(vec![LangVal::Integer(1)], Meta::default()), LangVal::Vector(vec![LangVal::Float(1.0)], Meta::default()))
+        ];
 
+        for (a, b) in &pairs {
+            assert!(MapKey(a.clone()) == MapKey(b.clone()));
+            assert_eq!(key_hash_of(a), key_hash_of(b));
+        }
+    }
 
+    // a key is found by exactly the values that are = to it
+    #[test]
+    fn map_keys_agree_with_equality() {
+        let pairs = [
+            (ratio(1, 10), LangVal::Float(0.1)),
+            (LangVal::Integer(9007199254740993), LangVal::Float(9007199254740992.0)),
+            (ratio(1, 2), LangVal::Float(0.5)),
+            (LangVal::String("a".to_string()), LangVal::Symbol("a".to_string()))
+        ];
+
+        for (a, b) in &pairs {
+            assert_eq!(MapKey(a.clone()) == MapKey(b.clone()), a == b);
+
+            let mut map = Hashmap::new();
+            map.insert(MapKey(a.clone()), LangVal::Nil);
+            assert_eq!(map.contains_key(&MapKey(b.clone())), a == b);
+        }
+    }
+}